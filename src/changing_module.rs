@@ -0,0 +1,145 @@
+use std::net::TcpStream;
+
+use muzzman_lib::prelude::*;
+
+use crate::{
+    chunked::ChunkedDecoder, connection::Connection, content_encoding::ContentDecoder, error,
+    pool::Reusable, response::Headers,
+};
+
+/// Content types that are better handled by other modules, with the extension used to find them
+const CONTENT_TYPES: [(&str, &str); 6] = [
+    ("application/x-bittorrent", "torrent"),
+    ("application/vnd.apple.mpegurl", "m3u8"),
+    ("application/x-mpegurl", "m3u8"),
+    ("audio/mpegurl", "m3u8"),
+    ("application/dash+xml", "mpd"),
+    ("application/metalink4+xml", "meta4"),
+];
+
+/// Why the element should be given to a other module
+/// after a 101 the upgraded socket is given in the storage as a non-blocking `TcpStream`
+pub struct Handoff {
+    pub reason: String,
+    /// For finding the module with `accept_extension`
+    pub extension: Option<String>,
+    /// If no module is found can continue downloading
+    pub can_continue: bool,
+}
+
+impl Handoff {
+    pub fn from_content_type(headers: &Headers) -> Option<Self> {
+        let content_type = headers.get("Content-Type")?;
+        let mime = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_lowercase();
+
+        CONTENT_TYPES
+            .iter()
+            .find(|(content_type, _)| *content_type == mime)
+            .map(|(content_type, extension)| Self {
+                reason: format!("Content-Type: {}", content_type),
+                extension: Some(extension.to_string()),
+                can_continue: true,
+            })
+    }
+
+    pub fn switching_protocols(headers: &Headers) -> Self {
+        Self {
+            reason: format!(
+                "101 Switching Protocols to {}",
+                headers.get("Upgrade").unwrap_or("unknown")
+            ),
+            extension: None,
+            can_continue: false,
+        }
+    }
+
+    /// A redirect to a url that is not http like `magnet:`
+    pub fn redirect(scheme: &str) -> Self {
+        Self {
+            reason: format!("Redirect to {}:", scheme),
+            extension: None,
+            can_continue: false,
+        }
+    }
+}
+
+/// Finds a module that accepts the url or the extension and gives the element to it
+/// the data that was downloaded until now is kept
+pub fn changing_module(element: &ERow, storage: &mut Storage) -> Result<(), SessionError> {
+    let Some(handoff) = storage.remove::<Handoff>() else {
+        return Err(error(element, "Error: Changing module without a reason"));
+    };
+
+    log::info!("Changing module: {}", handoff.reason);
+
+    let (info, url, mut name, current) = {
+        let element = element.read().unwrap();
+        (
+            element.info.clone(),
+            element.url.clone().unwrap_or_default(),
+            element.name.clone(),
+            element.module.as_ref().map(|module| module.id()),
+        )
+    };
+
+    if let Some(extension) = &handoff.extension {
+        if !name.to_lowercase().ends_with(&format!(".{}", extension)) {
+            name.push('.');
+            name.push_str(extension);
+        }
+    }
+
+    let mut found = None;
+    if let Ok(session) = info.get_session() {
+        let len = session.get_modules_len().unwrap_or(0);
+        for module in session.get_modules(0..len).unwrap_or_default() {
+            // we are the one that is giving it
+            if Some(module.id()) == current {
+                continue;
+            }
+
+            let by_url = module.accept_url(url.clone()).unwrap_or(false);
+            let by_extension =
+                handoff.extension.is_some() && module.accept_extension(&name).unwrap_or(false);
+
+            if by_url || by_extension {
+                found = Some(module);
+                break;
+            }
+        }
+    }
+
+    let Some(module) = found else {
+        storage.remove::<TcpStream>();
+        if handoff.can_continue {
+            log::info!("No module for {}, continue downloading", handoff.reason);
+            element.set_status(3);
+            return Ok(());
+        }
+        return Err(error(
+            element,
+            format!("Error: No module can continue after {}", handoff.reason),
+        ));
+    };
+
+    log::info!("Giving the element to: {:?}", module.get_name());
+
+    storage.remove::<Connection>();
+    storage.remove::<ChunkedDecoder>();
+    storage.remove::<ContentDecoder>();
+    storage.remove::<Reusable>();
+
+    if let Err(err) = info.set_module(Some(module.id())) {
+        return Err(error(
+            element,
+            format!("Error: Cannot change module: {:?}", err),
+        ));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,466 @@
+use std::io::{Read, Seek, SeekFrom};
+
+use base64::Engine;
+use muzzman_lib::prelude::*;
+use sha2::Digest;
+
+use crate::{error, response::Headers};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Algorithm {
+    Md5,
+    Sha1,
+    Sha256,
+    Sha512,
+    Blake3,
+}
+
+impl Algorithm {
+    /// Like `sha256`, `SHA-256` or `sha` from the `Digest` header
+    pub fn new(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().replace('-', "").as_str() {
+            "md5" => Some(Self::Md5),
+            "sha" | "sha1" => Some(Self::Sha1),
+            "sha256" => Some(Self::Sha256),
+            "sha512" => Some(Self::Sha512),
+            "blake3" => Some(Self::Blake3),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Md5 => "md5",
+            Self::Sha1 => "sha1",
+            Self::Sha256 => "sha256",
+            Self::Sha512 => "sha512",
+            Self::Blake3 => "blake3",
+        }
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            Self::Md5 => 16,
+            Self::Sha1 => 20,
+            Self::Sha256 | Self::Blake3 => 32,
+            Self::Sha512 => 64,
+        }
+    }
+
+    /// Only from the length, 32 bytes is sha256 not blake3
+    fn guess(len: usize) -> Option<Self> {
+        match len {
+            16 => Some(Self::Md5),
+            20 => Some(Self::Sha1),
+            32 => Some(Self::Sha256),
+            64 => Some(Self::Sha512),
+            _ => None,
+        }
+    }
+
+    fn hasher(&self) -> Hasher {
+        match self {
+            Self::Md5 => Hasher::Md5(md5::Md5::new()),
+            Self::Sha1 => Hasher::Sha1(sha1::Sha1::new()),
+            Self::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+            Self::Sha512 => Hasher::Sha512(sha2::Sha512::new()),
+            Self::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+}
+
+enum Hasher {
+    Md5(md5::Md5),
+    Sha1(sha1::Sha1),
+    Sha256(sha2::Sha256),
+    Sha512(sha2::Sha512),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Md5(hasher) => hasher.update(data),
+            Hasher::Sha1(hasher) => hasher.update(data),
+            Hasher::Sha256(hasher) => hasher.update(data),
+            Hasher::Sha512(hasher) => hasher.update(data),
+            Hasher::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    fn finalize(self) -> Vec<u8> {
+        match self {
+            Hasher::Md5(hasher) => hasher.finalize().to_vec(),
+            Hasher::Sha1(hasher) => hasher.finalize().to_vec(),
+            Hasher::Sha256(hasher) => hasher.finalize().to_vec(),
+            Hasher::Sha512(hasher) => hasher.finalize().to_vec(),
+            Hasher::Blake3(hasher) => hasher.finalize().as_bytes().to_vec(),
+        }
+    }
+}
+
+/// A hash that the data should have and from where is it
+#[derive(Clone, Debug)]
+pub struct Expected {
+    pub algorithm: Algorithm,
+    pub hash: Vec<u8>,
+    pub source: &'static str,
+}
+
+/// The value is hex or base64, base64 is how the headers are sending it
+fn decode_hash(value: &str, algorithm: Option<Algorithm>) -> Option<(Algorithm, Vec<u8>)> {
+    let value = value.trim();
+    let hash = if value.len().is_multiple_of(2) && value.bytes().all(|b| b.is_ascii_hexdigit()) {
+        (0..value.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&value[i..i + 2], 16).ok())
+            .collect::<Option<Vec<_>>>()?
+    } else {
+        base64::engine::general_purpose::STANDARD
+            .decode(value)
+            .or_else(|_| base64::engine::general_purpose::STANDARD_NO_PAD.decode(value))
+            .ok()?
+    };
+
+    let algorithm = match algorithm {
+        Some(algorithm) => algorithm,
+        None => Algorithm::guess(hash.len())?,
+    };
+    if hash.len() != algorithm.size() {
+        return None;
+    }
+    Some((algorithm, hash))
+}
+
+/// `expected-checksum` like `sha256:<hex>`, `sha512=<base64>` or only the hex
+/// without the name is guessed from the length
+pub fn parse_checksum(value: &str) -> Result<Expected, String> {
+    let invalid = || format!("Invalid expected-checksum: {}", value);
+    let (algorithm, hash) = match value.split_once([':', '=']) {
+        Some((name, hash)) if Algorithm::new(name).is_some() => (Algorithm::new(name), hash),
+        _ => (None, value),
+    };
+    let (algorithm, hash) = decode_hash(hash, algorithm).ok_or_else(invalid)?;
+    Ok(Expected {
+        algorithm,
+        hash,
+        source: "expected-checksum",
+    })
+}
+
+/// `Repr-Digest` (RFC 9530), `Digest` (RFC 3230) and `Content-MD5`
+/// the unknown algorithms are ignored
+pub fn from_headers(headers: &Headers, partial: bool) -> Vec<Expected> {
+    let mut expected = Vec::new();
+
+    for (header, source) in [("Repr-Digest", "Repr-Digest"), ("Digest", "Digest")] {
+        for value in headers.get_all(header) {
+            for item in value.split(',') {
+                let Some((name, hash)) = item.split_once('=') else {
+                    continue;
+                };
+                let Some(algorithm) = Algorithm::new(name) else {
+                    continue;
+                };
+                // a structured field byte sequence is between `:`
+                let hash = hash.trim().trim_matches(':');
+                if let Some((algorithm, hash)) = decode_hash(hash, Some(algorithm)) {
+                    expected.push(Expected {
+                        algorithm,
+                        hash,
+                        source,
+                    });
+                }
+            }
+        }
+    }
+
+    // is only for the part that was sent
+    if !partial {
+        if let Some((algorithm, hash)) = headers
+            .get("Content-MD5")
+            .and_then(|hash| decode_hash(hash, Some(Algorithm::Md5)))
+        {
+            expected.push(Expected {
+                algorithm,
+                hash,
+                source: "Content-MD5",
+            });
+        }
+    }
+
+    expected
+}
+
+/// Is in the element storage while downloading, the data is hashed when is writen
+pub struct Checksum {
+    expected: Vec<Expected>,
+    hashers: Vec<(Algorithm, Hasher)>,
+    /// How much was hashed, from the start of the data
+    position: usize,
+    /// If false the data is hashed from `element.data` at the end
+    incremental: bool,
+    /// The data is hashed from `element.data` by `complete`
+    rehashing: bool,
+}
+
+/// After the response headers, `resume_from` is where a partial response starts
+/// the server hashes are not used if the data is decoded, they are for the encoded data
+pub fn start(
+    element: &ERow,
+    storage: &mut Storage,
+    headers: &Headers,
+    resume_from: Option<usize>,
+    decoded: bool,
+) -> Result<(), SessionError> {
+    let mut expected = Vec::new();
+
+    let setting = match element
+        .read()
+        .unwrap()
+        .element_data
+        .get("expected-checksum")
+    {
+        Some(Type::String(value)) if !value.trim().is_empty() => Some(value.clone()),
+        _ => None,
+    };
+    if let Some(setting) = setting {
+        expected.push(parse_checksum(&setting).map_err(|err| error(element, err))?);
+    }
+
+    if !decoded {
+        expected.extend(from_headers(headers, resume_from.is_some()));
+    }
+
+    let previous = storage.remove::<Checksum>();
+    if expected.is_empty() {
+        return Ok(());
+    }
+
+    for expected in expected.iter() {
+        log::info!(
+            "Expected {} from {}: {}",
+            expected.algorithm.name(),
+            expected.source,
+            hex(&expected.hash)
+        );
+    }
+
+    // a resumed download can continue the same hashers only if nothing was lost
+    let checksum = match (previous, resume_from) {
+        (Some(mut previous), Some(from)) if previous.incremental && previous.position == from => {
+            for expected in expected.iter() {
+                if !previous
+                    .hashers
+                    .iter()
+                    .any(|(algorithm, _)| *algorithm == expected.algorithm)
+                {
+                    previous.incremental = false;
+                }
+            }
+            previous.expected = expected;
+            previous
+        }
+        (_, resume_from) => Checksum {
+            hashers: hashers(&expected),
+            expected,
+            position: resume_from.unwrap_or(0),
+            incremental: resume_from.is_none(),
+            rehashing: false,
+        },
+    };
+
+    storage.set(checksum);
+    Ok(())
+}
+
+fn hashers(expected: &[Expected]) -> Vec<(Algorithm, Hasher)> {
+    let mut hashers: Vec<(Algorithm, Hasher)> = Vec::new();
+    for expected in expected.iter() {
+        if !hashers
+            .iter()
+            .any(|(algorithm, _)| *algorithm == expected.algorithm)
+        {
+            hashers.push((expected.algorithm, expected.algorithm.hasher()));
+        }
+    }
+    hashers
+}
+
+/// The data that was writen in `element.data`, in order
+pub fn update(storage: &mut Storage, data: &[u8]) {
+    if let Some(checksum) = storage.get_mut::<Checksum>() {
+        if checksum.incremental {
+            for (_, hasher) in checksum.hashers.iter_mut() {
+                hasher.update(data);
+            }
+        }
+        checksum.position += data.len();
+    }
+}
+
+/// The segments are writen out of order
+pub fn not_incremental(storage: &mut Storage) {
+    if let Some(checksum) = storage.get_mut::<Checksum>() {
+        checksum.incremental = false;
+    }
+}
+
+/// Everything was downloaded, if the hashes match the element is complited
+/// the data that was not hashed when was writen is hashed a `buffer_size` every tick,
+/// `downloading` comes back here while is rehashing
+pub fn complete(element: &ERow, storage: &mut Storage) -> Result<(), SessionError> {
+    if let Some(mut checksum) = storage.remove::<Checksum>() {
+        if !checksum.incremental {
+            log::info!("Hashing the downloaded data");
+            checksum.hashers = hashers(&checksum.expected);
+            checksum.position = 0;
+            checksum.incremental = true;
+            checksum.rehashing = true;
+        }
+
+        if checksum.rehashing {
+            match rehash(element, &mut checksum) {
+                Ok(true) => {}
+                Ok(false) => {
+                    storage.set(checksum);
+                    return Ok(());
+                }
+                Err(err) => {
+                    return Err(error(
+                        element,
+                        format!("Error: Cannot read the data for the checksum: {}", err),
+                    ))
+                }
+            }
+        }
+
+        let hashes = checksum
+            .hashers
+            .into_iter()
+            .map(|(algorithm, hasher)| (algorithm, hasher.finalize()))
+            .collect::<Vec<_>>();
+
+        for expected in checksum.expected.iter() {
+            let Some((_, hash)) = hashes
+                .iter()
+                .find(|(algorithm, _)| *algorithm == expected.algorithm)
+            else {
+                continue;
+            };
+            if *hash != expected.hash {
+                return Err(error(
+                    element,
+                    format!(
+                        "Error: Checksum mismatch, {} from {} expected: {} but got: {}",
+                        expected.algorithm.name(),
+                        expected.source,
+                        hex(&expected.hash),
+                        hex(hash)
+                    ),
+                ));
+            }
+            log::info!("Checksum {} verified", expected.algorithm.name());
+        }
+    }
+
+    element.set_status(8);
+    Ok(())
+}
+
+/// If `complete` is hashing the data
+pub fn is_rehashing(storage: &Storage) -> bool {
+    storage
+        .get::<Checksum>()
+        .is_some_and(|checksum| checksum.rehashing)
+}
+
+/// Hashes the next `buffer_size` of `element.data`, true when is at the end
+fn rehash(element: &ERow, checksum: &mut Checksum) -> std::io::Result<bool> {
+    let mut element = element.write().unwrap();
+    let buffer_size = match element.settings.get("buffer_size") {
+        Some(Type::USize(len)) if *len > 0 => *len,
+        _ => 64 * 1024,
+    };
+
+    let mut buffer = vec![0; buffer_size];
+    let res = element
+        .data
+        .seek(SeekFrom::Start(checksum.position as u64))
+        .and_then(|_| loop {
+            match element.data.read(&mut buffer) {
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+                res => break res,
+            }
+        });
+    let _ = element.data.seek(SeekFrom::End(0));
+
+    let len = res?;
+    for (_, hasher) in checksum.hashers.iter_mut() {
+        hasher.update(&buffer[0..len]);
+    }
+    checksum.position += len;
+    Ok(len == 0)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// sha256 of `abc`
+    const SHA256_HEX: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+    const SHA256_BASE64: &str = "ungWv48Bz+pBQUDeXa4iI7ADYaOWF3qctBD/YfIAFa0=";
+
+    #[test]
+    fn hex_and_base64() {
+        let (algorithm, hex) = decode_hash(SHA256_HEX, None).unwrap();
+        assert_eq!(algorithm, Algorithm::Sha256);
+        let (_, base64) = decode_hash(SHA256_BASE64, None).unwrap();
+        assert_eq!(hex, base64);
+
+        let (_, no_pad) = decode_hash(SHA256_BASE64.trim_end_matches('='), None).unwrap();
+        assert_eq!(hex, no_pad);
+
+        let (_, upper) = decode_hash(&format!(" {} ", SHA256_HEX.to_uppercase()), None).unwrap();
+        assert_eq!(hex, upper);
+    }
+
+    #[test]
+    fn guessed_from_length() {
+        let md5 = "900150983cd24fb0d6963f7d28e17f72";
+        assert_eq!(decode_hash(md5, None).unwrap().0, Algorithm::Md5);
+        assert_eq!(
+            decode_hash(&"a".repeat(40), None).unwrap().0,
+            Algorithm::Sha1
+        );
+        assert!(decode_hash(&"a".repeat(30), None).is_none());
+    }
+
+    #[test]
+    fn wrong_length_for_algorithm() {
+        assert!(decode_hash(SHA256_HEX, Some(Algorithm::Md5)).is_none());
+        assert!(decode_hash(SHA256_HEX, Some(Algorithm::Sha256)).is_some());
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(decode_hash("", None).is_none());
+        assert!(decode_hash("not a hash!", None).is_none());
+        assert!(decode_hash(&"g".repeat(64), Some(Algorithm::Sha256)).is_none());
+    }
+
+    #[test]
+    fn expected_checksum() {
+        let expected = parse_checksum(&format!("sha256:{}", SHA256_HEX)).unwrap();
+        assert_eq!(expected.algorithm, Algorithm::Sha256);
+        let expected = parse_checksum(&format!("SHA-256={}", SHA256_BASE64)).unwrap();
+        assert_eq!(expected.algorithm, Algorithm::Sha256);
+        assert!(parse_checksum(&format!("md5:{}", SHA256_HEX)).is_err());
+    }
+}
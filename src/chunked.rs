@@ -0,0 +1,185 @@
+/// Max length of a chunk-size or trailer line, a server sending more is broken or hostile
+const MAX_LINE: usize = 8192;
+
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
+enum State {
+    #[default]
+    Size,
+    Data,
+    DataEnd,
+    Trailer,
+    Done,
+}
+
+/// Incremental decoder for `Transfer-Encoding: chunked` bodies
+///
+/// The raw bytes from the socket are feeded with `decode` and only the payload is written to the output,
+/// chunk extensions are ignored and trailers are collected in `trailers`.
+#[derive(Default)]
+pub struct ChunkedDecoder {
+    state: State,
+    remaining: usize,
+    line: Vec<u8>,
+    pub trailers: Vec<(String, String)>,
+}
+
+impl ChunkedDecoder {
+    /// Decodes `input` into `out`
+    /// Returns how many bytes from `input` was consumed, after the last chunk and the trailers the rest is not consumed
+    pub fn decode(&mut self, input: &[u8], out: &mut Vec<u8>) -> Result<usize, String> {
+        let mut i = 0;
+
+        while i < input.len() && self.state != State::Done {
+            match self.state {
+                State::Size => {
+                    let Some(line) = self.read_line(input, &mut i)? else {
+                        continue;
+                    };
+                    // chunk-ext is after `;` and we don't support any
+                    let size = line.split(';').next().unwrap_or("").trim();
+                    let Ok(size) = usize::from_str_radix(size, 16) else {
+                        return Err(format!("invalid chunk size: {:?}", size));
+                    };
+                    if size == 0 {
+                        self.state = State::Trailer;
+                    } else {
+                        self.remaining = size;
+                        self.state = State::Data;
+                    }
+                }
+                State::Data => {
+                    let len = self.remaining.min(input.len() - i);
+                    out.extend_from_slice(&input[i..i + len]);
+                    i += len;
+                    self.remaining -= len;
+                    if self.remaining == 0 {
+                        self.state = State::DataEnd;
+                    }
+                }
+                State::DataEnd => {
+                    let Some(line) = self.read_line(input, &mut i)? else {
+                        continue;
+                    };
+                    if !line.is_empty() {
+                        return Err("missing CRLF after chunk data".to_string());
+                    }
+                    self.state = State::Size;
+                }
+                State::Trailer => {
+                    let Some(line) = self.read_line(input, &mut i)? else {
+                        continue;
+                    };
+                    if line.is_empty() {
+                        self.state = State::Done;
+                    } else if let Some((name, value)) = line.split_once(':') {
+                        self.trailers
+                            .push((name.trim().to_owned(), value.trim().to_owned()));
+                    }
+                }
+                State::Done => {}
+            }
+        }
+
+        Ok(i)
+    }
+
+    /// Is true after the last chunk and the trailers was readed
+    pub fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    /// Reads until `\n`, if the line is not complete will keep what has and returns None
+    fn read_line(&mut self, input: &[u8], i: &mut usize) -> Result<Option<String>, String> {
+        while *i < input.len() {
+            let byte = input[*i];
+            *i += 1;
+            if byte == b'\n' {
+                if self.line.last() == Some(&b'\r') {
+                    self.line.pop();
+                }
+                let line = String::from_utf8_lossy(&self.line).into_owned();
+                self.line.clear();
+                return Ok(Some(line));
+            }
+            if self.line.len() >= MAX_LINE {
+                return Err("chunk line is too long".to_string());
+            }
+            self.line.push(byte);
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &[u8] =
+        b"4\r\nWiki\r\n6;name=value\r\npedia \r\nE\r\nin \r\n\r\nchunks.\r\n0\r\nExpires: never\r\nX-Sum:  abc \r\n\r\nnext";
+
+    #[test]
+    fn whole() {
+        let mut decoder = ChunkedDecoder::default();
+        let mut out = Vec::new();
+        let consumed = decoder.decode(BODY, &mut out).unwrap();
+        assert_eq!(out, b"Wikipedia in \r\n\r\nchunks.");
+        assert!(decoder.is_done());
+        // what is after the trailers is not from this body
+        assert_eq!(&BODY[consumed..], b"next");
+        assert_eq!(
+            decoder.trailers,
+            [
+                ("Expires".to_string(), "never".to_string()),
+                ("X-Sum".to_string(), "abc".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn split_reads() {
+        for size in 1..8 {
+            let mut decoder = ChunkedDecoder::default();
+            let mut out = Vec::new();
+            let mut consumed = 0;
+            for piece in BODY.chunks(size) {
+                consumed += decoder.decode(piece, &mut out).unwrap();
+                if decoder.is_done() {
+                    break;
+                }
+            }
+            assert_eq!(out, b"Wikipedia in \r\n\r\nchunks.", "size {}", size);
+            assert!(decoder.is_done());
+            assert_eq!(decoder.trailers.len(), 2);
+            assert_eq!(consumed, BODY.len() - 4);
+        }
+    }
+
+    #[test]
+    fn not_done() {
+        let mut decoder = ChunkedDecoder::default();
+        let mut out = Vec::new();
+        decoder.decode(b"5\r\nhel", &mut out).unwrap();
+        assert_eq!(out, b"hel");
+        assert!(!decoder.is_done());
+        decoder.decode(b"lo\r\n0\r\n", &mut out).unwrap();
+        assert_eq!(out, b"hello");
+        // the empty line after the trailers is missing
+        assert!(!decoder.is_done());
+        decoder.decode(b"\n", &mut out).unwrap();
+        assert!(decoder.is_done());
+    }
+
+    #[test]
+    fn invalid() {
+        let mut out = Vec::new();
+        assert!(ChunkedDecoder::default()
+            .decode(b"zz\r\n", &mut out)
+            .is_err());
+        assert!(ChunkedDecoder::default()
+            .decode(b"3\r\nabcd\r\n", &mut out)
+            .is_err());
+        assert!(ChunkedDecoder::default()
+            .decode(&[b'1'; MAX_LINE + 1], &mut out)
+            .is_err());
+    }
+}
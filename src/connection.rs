@@ -1,50 +1,169 @@
 use std::{
-    io::{Read, Write},
+    io::{self, Read, Write},
     net::TcpStream,
 };
 
-use rustls::ClientConnection;
+use rustls::{Certificate, ClientConnection};
 
+use crate::h2::H2Stream;
+
+/// The socket is non-blocking from when is opened, nothing waits for a slow peer
+/// what the socket cannot take is kept and sent on the next read or write
+#[allow(clippy::upper_case_acronyms, clippy::large_enum_variant)]
 pub enum Connection {
-    TCP(TcpStream),
+    /// With what was not writen yet
+    TCP(TcpStream, Vec<u8>),
+    /// The tls buffers what was not writen, without a limit
     TLSClient(ClientConnection, TcpStream),
+    H2(H2Stream),
 }
 
-impl Write for Connection {
-    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+impl Connection {
+    /// The chain sent by the server, none if is not TLS
+    pub fn peer_certificates(&self) -> Option<Vec<Certificate>> {
         match self {
-            Connection::TCP(conn) => {
-                let res = conn.write(buf);
-                let _ = self.flush();
-                res
+            Connection::TCP(..) => None,
+            Connection::TLSClient(trans, _) => {
+                trans.peer_certificates().map(|certs| certs.to_vec())
             }
-            Connection::TLSClient(trans, tcp) => trans.writer().write(buf),
+            Connection::H2(stream) => stream.peer_certificates(),
         }
     }
 
-    fn flush(&mut self) -> std::io::Result<()> {
+    /// If something was writen but is not sent yet
+    pub fn is_pending(&self) -> bool {
         match self {
-            Connection::TCP(conn) => conn.flush(),
-            Connection::TLSClient(trans, conn) => {
-                if trans.wants_read() {
-                    let _ = trans.read_tls(conn);
-                    let _ = trans.process_new_packets();
-                }
-                if trans.wants_write() {
-                    let _ = trans.write_tls(conn);
-                }
-                Ok(())
+            Connection::TCP(_, pending) => !pending.is_empty(),
+            Connection::TLSClient(trans, _) => trans.wants_write(),
+            Connection::H2(stream) => stream.is_pending(),
+        }
+    }
+
+    /// Sends what the socket can take now, the rest stays for the next time
+    fn send_pending(&mut self) -> io::Result<()> {
+        match self {
+            Connection::TCP(conn, pending) => send_pending(pending, |buf| conn.write(buf)),
+            Connection::TLSClient(trans, conn) => send_tls(trans, conn),
+            Connection::H2(_) => Ok(()),
+        }
+    }
+}
+
+/// Writes from `pending` until the socket would block
+pub fn send_pending(
+    pending: &mut Vec<u8>,
+    mut write: impl FnMut(&[u8]) -> io::Result<usize>,
+) -> io::Result<()> {
+    while !pending.is_empty() {
+        match write(pending) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(len) => {
+                pending.drain(0..len);
             }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
         }
     }
+    Ok(())
+}
+
+/// Writes the tls records until the socket would block
+pub fn send_tls(trans: &mut ClientConnection, tcp: &mut TcpStream) -> io::Result<()> {
+    while trans.wants_write() {
+        match trans.write_tls(tcp) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(_) => {}
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// A write never blocks and takes all of `buf`, `is_pending` is how to wait for a slow peer
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::TCP(_, pending) => pending.extend_from_slice(buf),
+            Connection::TLSClient(trans, _) => {
+                trans.writer().write_all(buf)?;
+            }
+            Connection::H2(stream) => return stream.write(buf),
+        }
+        self.send_pending()?;
+        Ok(buf.len())
+    }
+
+    /// Not all is sent if the socket is full, the rest is sent on the next read or write
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_pending()
+    }
 }
 
 impl Read for Connection {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let _ = self.flush();
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.send_pending()?;
         match self {
-            Connection::TCP(conn) => conn.read(buf),
-            Connection::TLSClient(trans, conn) => trans.reader().read(buf),
+            Connection::TCP(conn, _) => conn.read(buf),
+            Connection::TLSClient(trans, conn) => loop {
+                match trans.reader().read(buf) {
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                        // no plain text, should read more from the socket
+                        if trans.read_tls(conn)? == 0 {
+                            return Ok(0);
+                        }
+                        if let Err(err) = trans.process_new_packets() {
+                            return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+                        }
+                        // the server can want something like a key update
+                        send_tls(trans, conn)?;
+                    }
+                    res => return res,
+                }
+            },
+            Connection::H2(stream) => stream.read(buf),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, thread};
+
+    use super::*;
+
+    #[test]
+    fn slow_peer() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let tcp = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        tcp.set_nonblocking(true).unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+        let mut conn = Connection::TCP(tcp, Vec::new());
+
+        // the peer is not reading, the socket is full but the write does not wait
+        let buffer = vec![1; 1024 * 1024];
+        let mut writen = 0;
+        while !conn.is_pending() {
+            assert_eq!(conn.write(&buffer).unwrap(), buffer.len());
+            writen += buffer.len();
+            assert!(writen < 1024 * buffer.len());
+        }
+
+        let reader = thread::spawn(move || {
+            let mut recived = 0;
+            let mut buffer = vec![0; 64 * 1024];
+            while recived < writen {
+                recived += peer.read(&mut buffer).unwrap();
+            }
+            recived
+        });
+
+        while conn.is_pending() {
+            conn.flush().unwrap();
+            thread::yield_now();
         }
+        assert_eq!(reader.join().unwrap(), writen);
     }
 }
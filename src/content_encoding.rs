@@ -0,0 +1,311 @@
+use std::{
+    io::Write,
+    sync::{Arc, Mutex},
+};
+
+use zstd::stream::raw::Operation;
+
+/// What we send in `Accept-Encoding` when `compression` is enabled
+pub const ACCEPT_ENCODING: &str = "gzip, deflate, br, zstd";
+
+/// Every stage writes his output here, the next stage or `element.data` takes it
+#[derive(Clone, Default)]
+struct Sink(Arc<Mutex<Vec<u8>>>);
+
+impl Sink {
+    fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+enum Stage {
+    Gzip(flate2::write::GzDecoder<Sink>),
+    /// `deflate` should be zlib but some servers send raw deflate,
+    /// is decided after the first two bytes, true when the stream ended
+    Deflate(Option<flate2::Decompress>, Vec<u8>, bool),
+    Brotli(Box<brotli_decompressor::DecompressorWriter<Sink>>),
+    /// The zstd context is only Send, true when the frame ended
+    Zstd(Mutex<zstd::stream::raw::Decoder<'static>>, bool),
+}
+
+struct Decoder {
+    stage: Stage,
+    sink: Sink,
+}
+
+impl Decoder {
+    fn new(coding: &str) -> Result<Self, String> {
+        let sink = Sink::default();
+        let stage = match coding {
+            "gzip" | "x-gzip" => Stage::Gzip(flate2::write::GzDecoder::new(sink.clone())),
+            "deflate" => Stage::Deflate(None, Vec::new(), false),
+            "br" => Stage::Brotli(Box::new(brotli_decompressor::DecompressorWriter::new(
+                sink.clone(),
+                4096,
+            ))),
+            "zstd" => Stage::Zstd(
+                Mutex::new(zstd::stream::raw::Decoder::new().map_err(|err| err.to_string())?),
+                false,
+            ),
+            coding => return Err(format!("Unsupported Content-Encoding: {}", coding)),
+        };
+        Ok(Self { stage, sink })
+    }
+
+    fn decode(&mut self, input: &[u8]) -> std::io::Result<Vec<u8>> {
+        if input.is_empty() {
+            return Ok(self.sink.take());
+        }
+        match &mut self.stage {
+            Stage::Gzip(decoder) => {
+                decoder.write_all(input)?;
+                decoder.flush()?;
+            }
+            Stage::Deflate(decoder, head, ended) => {
+                let mut input = input;
+                if decoder.is_none() {
+                    head.extend_from_slice(input);
+                    if head.len() < 2 {
+                        return Ok(Vec::new());
+                    }
+                    // zlib header: CM is 8 and CMF FLG is multiple of 31
+                    let zlib = head[0] & 0x0f == 8
+                        && (u16::from(head[0]) << 8 | u16::from(head[1])) % 31 == 0;
+                    *decoder = Some(flate2::Decompress::new(zlib));
+                    input = head.as_slice();
+                }
+                if let Some(decoder) = decoder {
+                    if !*ended {
+                        *ended = inflate(decoder, input, &mut self.sink)?;
+                    }
+                }
+                head.clear();
+            }
+            Stage::Brotli(decoder) => {
+                decoder.write_all(input)?;
+                decoder.flush()?;
+            }
+            Stage::Zstd(decoder, ended) => {
+                let decoder = decoder.get_mut().unwrap();
+                let mut input = zstd::stream::raw::InBuffer::around(input);
+                let mut buffer = vec![0; 32 * 1024];
+                loop {
+                    let mut output = zstd::stream::raw::OutBuffer::around(&mut buffer[..]);
+                    // 0 is when a frame was fully decoded
+                    let hint = decoder.run(&mut input, &mut output)?;
+                    let writen = output.pos();
+                    *ended = hint == 0;
+                    self.sink.write_all(&buffer[..writen])?;
+                    if input.pos() == input.src.len() && (*ended || writen < buffer.len()) {
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(self.sink.take())
+    }
+
+    /// At the end of the body, what was left in the decoder
+    /// if the stream is not complete or the checksum is wrong is a error
+    fn finish(&mut self) -> std::io::Result<Vec<u8>> {
+        let incomplete = |coding| {
+            std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("the {} stream is incomplete", coding),
+            )
+        };
+        match &mut self.stage {
+            // checks also the CRC and ISIZE
+            Stage::Gzip(decoder) => decoder.try_finish()?,
+            Stage::Deflate(_, _, ended) => {
+                if !*ended {
+                    return Err(incomplete("deflate"));
+                }
+            }
+            Stage::Brotli(decoder) => decoder.close()?,
+            Stage::Zstd(_, ended) => {
+                if !*ended {
+                    return Err(incomplete("zstd"));
+                }
+            }
+        }
+        Ok(self.sink.take())
+    }
+}
+
+/// Decompresses all the `input` in `sink`, true when the end of the stream was reached
+fn inflate(
+    decompress: &mut flate2::Decompress,
+    mut input: &[u8],
+    sink: &mut Sink,
+) -> std::io::Result<bool> {
+    let mut output = Vec::with_capacity(32 * 1024);
+    loop {
+        output.clear();
+        let before = decompress.total_in();
+        let status = decompress
+            .decompress_vec(input, &mut output, flate2::FlushDecompress::None)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+        input = &input[(decompress.total_in() - before) as usize..];
+        sink.write_all(&output)?;
+
+        if status == flate2::Status::StreamEnd {
+            return Ok(true);
+        }
+        // with space left in the output everything that can be decoded was
+        if output.len() < output.capacity()
+            && (input.is_empty() || status == flate2::Status::BufError)
+        {
+            return Ok(false);
+        }
+    }
+}
+
+/// Decodes the body like is said in `Content-Encoding`, while is downloading
+pub struct ContentDecoder {
+    /// In the order that should be decoded, the reverse of the header
+    decoders: Vec<Decoder>,
+}
+
+impl ContentDecoder {
+    /// From the `Content-Encoding` header, None if the body is not encoded
+    pub fn new(content_encoding: &str) -> Result<Option<Self>, String> {
+        let mut decoders = Vec::new();
+        for coding in content_encoding.split(',').rev() {
+            let coding = coding.trim().to_lowercase();
+            if coding.is_empty() || coding == "identity" {
+                continue;
+            }
+            decoders.push(Decoder::new(&coding)?);
+        }
+
+        if decoders.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(Self { decoders }))
+        }
+    }
+
+    pub fn decode(&mut self, input: Vec<u8>) -> Result<Vec<u8>, String> {
+        let mut data = input;
+        for decoder in self.decoders.iter_mut() {
+            data = decoder
+                .decode(&data)
+                .map_err(|err| format!("Cannot decode body: {}", err))?;
+        }
+        Ok(data)
+    }
+
+    /// When all the body was recived, the rest of the decoded data
+    pub fn finish(&mut self) -> Result<Vec<u8>, String> {
+        let mut data = Vec::new();
+        for decoder in self.decoders.iter_mut() {
+            let mut output = decoder
+                .decode(&data)
+                .map_err(|err| format!("Cannot decode body: {}", err))?;
+            output.extend(
+                decoder
+                    .finish()
+                    .map_err(|err| format!("Cannot decode body: {}", err))?,
+            );
+            data = output;
+        }
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::{
+        write::{DeflateEncoder, GzEncoder, ZlibEncoder},
+        Compression,
+    };
+
+    use super::*;
+
+    const TEXT: &[u8] = b"the same text again and again, the same text again and again";
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn zlib(data: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn raw_deflate(data: &[u8]) -> Vec<u8> {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    /// Like the body comes, in small parts
+    fn decode(content_encoding: &str, body: &[u8], part: usize) -> Result<Vec<u8>, String> {
+        let mut decoder = ContentDecoder::new(content_encoding)?.unwrap();
+        let mut data = Vec::new();
+        for part in body.chunks(part) {
+            data.extend(decoder.decode(part.to_vec())?);
+        }
+        data.extend(decoder.finish()?);
+        Ok(data)
+    }
+
+    #[test]
+    fn deflate() {
+        let zlib = zlib(TEXT);
+        let raw = raw_deflate(TEXT);
+        assert_eq!(zlib[0] & 0x0f, 8);
+        assert_ne!(zlib, raw);
+
+        for part in [1, 5, 1024] {
+            assert_eq!(decode("deflate", &zlib, part).unwrap(), TEXT);
+            assert_eq!(decode("deflate", &raw, part).unwrap(), TEXT);
+        }
+    }
+
+    #[test]
+    fn chain() {
+        // `Content-Encoding: gzip, zstd` is gzip first and then zstd
+        let body = zstd::encode_all(gzip(TEXT).as_slice(), 0).unwrap();
+        assert_eq!(decode("gzip, zstd", &body, 7).unwrap(), TEXT);
+        assert_eq!(
+            decode(" X-Gzip , identity, zstd", &body, 1024).unwrap(),
+            TEXT
+        );
+        assert!(decode("zstd, gzip", &body, 1024).is_err());
+
+        assert!(ContentDecoder::new("identity").unwrap().is_none());
+        assert!(ContentDecoder::new("").unwrap().is_none());
+        assert!(ContentDecoder::new("gzip, compress").is_err());
+    }
+
+    #[test]
+    fn truncated() {
+        let zlib = zlib(TEXT);
+        let gzip = gzip(TEXT);
+        let zstd = zstd::encode_all(TEXT, 0).unwrap();
+
+        for (coding, body) in [("deflate", zlib), ("gzip", gzip), ("zstd", zstd)] {
+            let truncated = &body[0..body.len() - 4];
+            assert!(decode(coding, truncated, 1024).is_err(), "{}", coding);
+            assert_eq!(decode(coding, &body, 1024).unwrap(), TEXT, "{}", coding);
+        }
+        // only the start of the zlib header
+        assert!(decode("deflate", &[0x78], 1024).is_err());
+    }
+}
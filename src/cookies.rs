@@ -0,0 +1,656 @@
+use std::{
+    collections::HashSet,
+    io::Write,
+    sync::{Mutex, OnceLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use muzzman_lib::prelude::*;
+use url::Url;
+
+use crate::response::Headers;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+#[derive(Clone, Debug)]
+pub struct Cookie {
+    pub name: String,
+    pub value: String,
+    /// Lowercase without the leading `.`
+    pub domain: String,
+    /// Without `Domain` is only sent to the same host, not to subdomains
+    pub host_only: bool,
+    pub path: String,
+    pub secure: bool,
+    pub http_only: bool,
+    /// None is a session cookie
+    pub expires: Option<SystemTime>,
+    pub same_site: SameSite,
+    creation: SystemTime,
+}
+
+impl Cookie {
+    /// RFC 6265 5.2, None if the cookie should be ignored
+    pub fn parse(set_cookie: &str, url: &Url) -> Option<Self> {
+        let host = url.host_str()?.to_lowercase();
+        let now = SystemTime::now();
+
+        let mut parts = set_cookie.split(';');
+        let (name, value) = parts.next()?.split_once('=')?;
+        let (name, value) = (name.trim(), value.trim());
+        if name.is_empty() {
+            return None;
+        }
+
+        let mut cookie = Self {
+            name: name.to_string(),
+            value: value.to_string(),
+            domain: host.clone(),
+            host_only: true,
+            path: default_path(url),
+            secure: false,
+            http_only: false,
+            expires: None,
+            same_site: SameSite::Lax,
+            creation: now,
+        };
+
+        let mut max_age = None;
+        for attribute in parts {
+            let (key, value) = attribute.split_once('=').unwrap_or((attribute, ""));
+            let (key, value) = (key.trim().to_lowercase(), value.trim());
+            match key.as_str() {
+                "expires" => {
+                    if let Some(expires) = parse_cookie_date(value) {
+                        cookie.expires = Some(expires);
+                    }
+                }
+                "max-age" => {
+                    if let Ok(seconds) = value.parse::<i64>() {
+                        max_age = Some(if seconds <= 0 {
+                            UNIX_EPOCH
+                        } else {
+                            add_seconds(now, seconds as u64)
+                        });
+                    }
+                }
+                "domain" => {
+                    let domain = value.trim_start_matches('.').to_lowercase();
+                    if !domain.is_empty() {
+                        if !domain_match(&host, &domain) {
+                            return None;
+                        }
+                        // a public suffix like `co.uk` is only for the same host, not for all its sites
+                        if is_public_suffix(&domain) {
+                            if domain != host {
+                                return None;
+                            }
+                            continue;
+                        }
+                        cookie.domain = domain;
+                        cookie.host_only = false;
+                    }
+                }
+                "path" if value.starts_with('/') => cookie.path = value.to_string(),
+                "secure" => cookie.secure = true,
+                "httponly" => cookie.http_only = true,
+                "samesite" => {
+                    cookie.same_site = match value.to_lowercase().as_str() {
+                        "strict" => SameSite::Strict,
+                        "none" => SameSite::None,
+                        _ => SameSite::Lax,
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        // Max-Age is more important then Expires
+        if max_age.is_some() {
+            cookie.expires = max_age;
+        }
+
+        // a insecure site cannot set a secure cookie, and SameSite=None should be secure
+        if cookie.secure && !is_secure(url) {
+            return None;
+        }
+        if cookie.same_site == SameSite::None && !cookie.secure {
+            return None;
+        }
+
+        Some(cookie)
+    }
+
+    fn is_expired(&self, now: SystemTime) -> bool {
+        matches!(self.expires, Some(expires) if expires <= now)
+    }
+
+    fn matches(&self, url: &Url) -> bool {
+        let Some(host) = url.host_str() else {
+            return false;
+        };
+        let host = host.to_lowercase();
+
+        let domain = if self.host_only {
+            host == self.domain
+        } else {
+            domain_match(&host, &self.domain)
+        };
+
+        domain && path_match(url.path(), &self.path) && (!self.secure || is_secure(url))
+    }
+}
+
+fn is_secure(url: &Url) -> bool {
+    matches!(url.scheme(), "https" | "wss")
+}
+
+fn domain_match(host: &str, domain: &str) -> bool {
+    host == domain
+        || (host.ends_with(domain)
+            && host[..host.len() - domain.len()].ends_with('.')
+            && host.parse::<std::net::IpAddr>().is_err())
+}
+
+fn is_public_suffix(domain: &str) -> bool {
+    psl::suffix_str(domain) == Some(domain)
+}
+
+fn path_match(path: &str, cookie_path: &str) -> bool {
+    path == cookie_path
+        || (path.starts_with(cookie_path)
+            && (cookie_path.ends_with('/') || path[cookie_path.len()..].starts_with('/')))
+}
+
+/// The directory of the url path
+fn default_path(url: &Url) -> String {
+    let path = url.path();
+    match path.rfind('/') {
+        Some(0) | None => "/".to_string(),
+        Some(i) => path[..i].to_string(),
+    }
+}
+
+/// 9999-12-31 23:59:59, the latest date that is used
+const MAX_DATE: u64 = 253402300799;
+
+/// Like `Max-Age=9223372036854775807` should not overflow, RFC 6265 5.2.2 says to use the latest date
+fn add_seconds(time: SystemTime, seconds: u64) -> SystemTime {
+    let max = UNIX_EPOCH + Duration::from_secs(MAX_DATE);
+    time.checked_add(Duration::from_secs(seconds))
+        .map_or(max, |time| time.min(max))
+}
+
+/// RFC 6265 5.1.1, accepts every format that browsers are accepting
+pub fn parse_cookie_date(date: &str) -> Option<SystemTime> {
+    let mut time = None;
+    let mut day = None;
+    let mut month = None;
+    let mut year = None;
+
+    let is_delimiter = |c: char| {
+        c == '\t'
+            || (' '..='/').contains(&c)
+            || (';'..='@').contains(&c)
+            || ('['..='`').contains(&c)
+            || ('{'..='~').contains(&c)
+    };
+
+    for token in date.split(is_delimiter).filter(|token| !token.is_empty()) {
+        if time.is_none() {
+            let parts = token.split(':').collect::<Vec<_>>();
+            if parts.len() == 3 {
+                let numbers = parts
+                    .iter()
+                    .map(|part| {
+                        let digits = part
+                            .chars()
+                            .take_while(|c| c.is_ascii_digit())
+                            .collect::<String>();
+                        digits
+                            .parse::<u64>()
+                            .ok()
+                            .filter(|_| (1..=2).contains(&digits.len()))
+                    })
+                    .collect::<Option<Vec<_>>>();
+                if let Some(numbers) = numbers {
+                    time = Some((numbers[0], numbers[1], numbers[2]));
+                    continue;
+                }
+            }
+        }
+
+        let digits = token
+            .chars()
+            .take_while(|c| c.is_ascii_digit())
+            .collect::<String>();
+
+        if day.is_none() && (1..=2).contains(&digits.len()) && digits.len() == token.len() {
+            day = digits.parse::<u64>().ok();
+            continue;
+        }
+
+        if let (None, Some(name)) = (month, token.get(..3)) {
+            let name = name.to_lowercase();
+            let months = [
+                "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+            ];
+            if let Some(i) = months.iter().position(|m| *m == name) {
+                month = Some(i as u64 + 1);
+                continue;
+            }
+        }
+
+        if year.is_none() && (2..=4).contains(&digits.len()) {
+            year = digits.parse::<u64>().ok();
+            continue;
+        }
+    }
+
+    let (hour, minute, second) = time?;
+    let (day, month, mut year) = (day?, month?, year?);
+    if (70..=99).contains(&year) {
+        year += 1900;
+    } else if year <= 69 {
+        year += 2000;
+    }
+
+    if !(1..=31).contains(&day) || year < 1601 || hour > 23 || minute > 59 || second > 59 {
+        return None;
+    }
+
+    let days = days_from_civil(year as i64, month as i64, day as i64);
+    let seconds = days * 86400 + (hour * 3600 + minute * 60 + second) as i64;
+    if seconds < 0 {
+        return Some(UNIX_EPOCH);
+    }
+    Some(UNIX_EPOCH + Duration::from_secs(seconds as u64))
+}
+
+/// Days from 1970-01-01, from Howard Hinnant's algorithm
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = (if year >= 0 { year } else { year - 399 }) / 400;
+    let yoe = year - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Every cookie of the module, shared by all the elements
+#[derive(Default)]
+pub struct CookieJar {
+    cookies: Vec<Cookie>,
+    /// The `cookie-file` that were already imported
+    loaded: HashSet<String>,
+}
+
+impl CookieJar {
+    pub fn insert(&mut self, cookie: Cookie) {
+        let old = self.cookies.iter().position(|old| {
+            old.name == cookie.name && old.domain == cookie.domain && old.path == cookie.path
+        });
+
+        let mut cookie = cookie;
+        if let Some(old) = old {
+            cookie.creation = self.cookies.remove(old).creation;
+        }
+
+        if !cookie.is_expired(SystemTime::now()) {
+            self.cookies.push(cookie);
+        }
+    }
+
+    /// From every `Set-Cookie` of the response, returns if something was changed
+    pub fn set_cookies(&mut self, url: &Url, headers: &Headers) -> bool {
+        let mut changed = false;
+        for set_cookie in headers.get_all("Set-Cookie") {
+            if let Some(cookie) = Cookie::parse(set_cookie, url) {
+                log::info!("Cookie set: {} for {}", cookie.name, cookie.domain);
+                self.insert(cookie);
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// The `Cookie` header, the longer paths are first and then the older
+    /// `cross_site` is when a redirect went to a other site
+    pub fn cookie_header(&mut self, url: &Url, method: &str, cross_site: bool) -> Option<String> {
+        let now = SystemTime::now();
+        self.cookies.retain(|cookie| !cookie.is_expired(now));
+
+        let safe_method = matches!(method, "GET" | "HEAD" | "OPTIONS" | "TRACE");
+        let mut cookies = self
+            .cookies
+            .iter()
+            .filter(|cookie| cookie.matches(url))
+            .filter(|cookie| match cookie.same_site {
+                SameSite::Strict => !cross_site,
+                SameSite::Lax => !cross_site || safe_method,
+                SameSite::None => true,
+            })
+            .collect::<Vec<_>>();
+
+        if cookies.is_empty() {
+            return None;
+        }
+
+        cookies.sort_by(|a, b| {
+            b.path
+                .len()
+                .cmp(&a.path.len())
+                .then(a.creation.cmp(&b.creation))
+        });
+
+        Some(
+            cookies
+                .iter()
+                .map(|cookie| format!("{}={}", cookie.name, cookie.value))
+                .collect::<Vec<_>>()
+                .join("; "),
+        )
+    }
+
+    /// Netscape `cookies.txt` like curl and the browser extensions are making,
+    /// `#HttpOnly_` before the domain is for http only cookies, returns how many were imported
+    pub fn import_netscape(&mut self, text: &str) -> usize {
+        let now = SystemTime::now();
+        let mut count = 0;
+        for line in text.lines() {
+            let (line, http_only) = match line.strip_prefix("#HttpOnly_") {
+                Some(line) => (line, true),
+                None => (line, false),
+            };
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields = line.split('\t').collect::<Vec<_>>();
+            if fields.len() < 7 {
+                log::warn!("Invalid cookies.txt line: {:?}", line);
+                continue;
+            }
+
+            let expires = fields[4].trim().parse::<u64>().unwrap_or(0);
+            let domain = fields[0].trim().to_lowercase();
+            let cookie = Cookie {
+                name: fields[5].to_string(),
+                value: fields[6].trim_end_matches('\r').to_string(),
+                host_only: !fields[1].eq_ignore_ascii_case("TRUE"),
+                domain: domain.trim_start_matches('.').to_string(),
+                path: fields[2].to_string(),
+                secure: fields[3].eq_ignore_ascii_case("TRUE"),
+                http_only,
+                expires: if expires == 0 {
+                    None
+                } else {
+                    Some(add_seconds(UNIX_EPOCH, expires))
+                },
+                same_site: SameSite::Lax,
+                creation: now,
+            };
+            if !cookie.host_only && is_public_suffix(&cookie.domain) {
+                log::warn!(
+                    "Ignored cookie {} for the public suffix {}",
+                    cookie.name,
+                    cookie.domain
+                );
+                continue;
+            }
+            self.insert(cookie);
+            count += 1;
+        }
+        count
+    }
+
+    /// The session cookies have 0 as expires
+    pub fn export_netscape(&self) -> String {
+        let mut text = String::from("# Netscape HTTP Cookie File\n# Saved by MuzzMan Http\n\n");
+        let now = SystemTime::now();
+        for cookie in self.cookies.iter().filter(|cookie| !cookie.is_expired(now)) {
+            let expires = cookie
+                .expires
+                .and_then(|expires| expires.duration_since(UNIX_EPOCH).ok())
+                .map(|expires| expires.as_secs())
+                .unwrap_or(0);
+            let flag = |value: bool| if value { "TRUE" } else { "FALSE" };
+            text.push_str(&format!(
+                "{}{}{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+                if cookie.http_only { "#HttpOnly_" } else { "" },
+                if cookie.host_only { "" } else { "." },
+                cookie.domain,
+                flag(!cookie.host_only),
+                cookie.path,
+                flag(cookie.secure),
+                expires,
+                cookie.name,
+                cookie.value
+            ));
+        }
+        text
+    }
+}
+
+pub fn jar() -> &'static Mutex<CookieJar> {
+    static JAR: OnceLock<Mutex<CookieJar>> = OnceLock::new();
+    JAR.get_or_init(|| Mutex::new(CookieJar::default()))
+}
+
+/// Where the jar is persisted, the `cookie-file` setting
+fn get_cookie_file(element: &ERow) -> Option<String> {
+    if let Some(Type::String(file)) = element.read().unwrap().settings.get("cookie-file") {
+        return Some(file.clone());
+    }
+    None
+}
+
+fn get_enabled(element: &ERow) -> bool {
+    !matches!(
+        element.read().unwrap().element_data.get("cookies"),
+        Some(Type::Bool(false))
+    )
+}
+
+/// The first time a `cookie-file` is used is imported in the jar
+fn load(element: &ERow, jar: &mut CookieJar) {
+    let Some(file) = get_cookie_file(element) else {
+        return;
+    };
+    if !jar.loaded.insert(file.clone()) {
+        return;
+    }
+
+    match std::fs::read_to_string(&file) {
+        Ok(text) => {
+            let count = jar.import_netscape(&text);
+            log::info!("Loaded {} cookies from {}", count, file);
+        }
+        Err(err) => log::info!("Cannot load cookies from {}: {}", file, err),
+    }
+}
+
+fn save(element: &ERow, jar: &CookieJar) {
+    let Some(file) = get_cookie_file(element) else {
+        return;
+    };
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    // the cookies are secrets like the passwords, only the user can read them
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let result = options
+        .open(&file)
+        .and_then(|mut opened| opened.write_all(jar.export_netscape().as_bytes()));
+    if let Err(err) = result {
+        log::warn!("Cannot save cookies to {}: {}", file, err);
+    }
+}
+
+/// The registrable domain of the host, one label more than the public suffix
+/// a ip or a host that is a public suffix is the site by itself
+fn site(url: &Url) -> String {
+    let host = url.host_str().unwrap_or_default().to_lowercase();
+    if host.parse::<std::net::IpAddr>().is_ok() || host.starts_with('[') {
+        return host;
+    }
+    psl::domain_str(&host).unwrap_or(&host).to_string()
+}
+
+/// The site of the first request, the redirects after are cross-site if they go to a other site
+pub struct FirstSite(String);
+
+/// Adds the cookies from the jar to `Cookie`, after the ones from the `headers` setting
+pub fn add_cookie_header(
+    element: &ERow,
+    storage: &mut Storage,
+    url: &Url,
+    method: &str,
+    headers: &mut std::collections::HashMap<String, String>,
+) {
+    if !get_enabled(element) {
+        return;
+    }
+
+    let redirects = match element.read().unwrap().settings.get("redirects") {
+        Some(Type::USize(redirects)) => *redirects,
+        _ => 0,
+    };
+    if redirects == 0 || storage.get::<FirstSite>().is_none() {
+        storage.set(FirstSite(site(url)));
+    }
+    let cross_site = storage
+        .get::<FirstSite>()
+        .map(|first| first.0 != site(url))
+        .unwrap_or(false);
+
+    let mut jar = jar().lock().unwrap();
+    load(element, &mut jar);
+    let Some(cookies) = jar.cookie_header(url, method, cross_site) else {
+        return;
+    };
+
+    match headers
+        .iter_mut()
+        .find(|(name, _)| name.eq_ignore_ascii_case("Cookie"))
+    {
+        Some((_, value)) => {
+            value.push_str("; ");
+            value.push_str(&cookies);
+        }
+        None => {
+            headers.insert("Cookie".to_string(), cookies);
+        }
+    }
+}
+
+/// Saves the `Set-Cookie` of the response in the jar and in the `cookie-file`
+pub fn store(element: &ERow, url: &Url, headers: &Headers) {
+    if !get_enabled(element) {
+        return;
+    }
+
+    let mut jar = jar().lock().unwrap();
+    load(element, &mut jar);
+    if jar.set_cookies(url, headers) {
+        save(element, &jar);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seconds(date: &str) -> Option<u64> {
+        parse_cookie_date(date).map(|time| time.duration_since(UNIX_EPOCH).unwrap().as_secs())
+    }
+
+    #[test]
+    fn formats() {
+        // RFC 1123, RFC 850 and asctime
+        assert_eq!(seconds("Sun, 06 Nov 1994 08:49:37 GMT"), Some(784111777));
+        assert_eq!(seconds("Sunday, 06-Nov-94 08:49:37 GMT"), Some(784111777));
+        assert_eq!(seconds("Sun Nov  6 08:49:37 1994"), Some(784111777));
+        assert_eq!(seconds("6 november 1994 8:49:37"), Some(784111777));
+    }
+
+    #[test]
+    fn two_digit_years() {
+        assert_eq!(seconds("Thu, 01 Jan 70 00:00:00 GMT"), Some(0));
+        assert_eq!(seconds("Sat, 01 Jan 00 00:00:00 GMT"), Some(946684800));
+        assert_eq!(seconds("Sat, 01 Jan 2000 00:00:00 GMT"), Some(946684800));
+    }
+
+    #[test]
+    fn before_epoch() {
+        assert_eq!(seconds("Sat, 01 Jan 1601 00:00:00 GMT"), Some(0));
+    }
+
+    #[test]
+    fn huge_max_age() {
+        let url = Url::parse("https://example.com/").unwrap();
+        let max = UNIX_EPOCH + Duration::from_secs(MAX_DATE);
+        let cookie = Cookie::parse("a=1; Max-Age=9223372036854775807", &url).unwrap();
+        assert_eq!(cookie.expires, Some(max));
+        let cookie = Cookie::parse("a=1; Max-Age=-9223372036854775808", &url).unwrap();
+        assert_eq!(cookie.expires, Some(UNIX_EPOCH));
+
+        let mut jar = CookieJar::default();
+        let text = "example.com\tFALSE\t/\tFALSE\t18446744073709551615\ta\t1\n";
+        assert_eq!(jar.import_netscape(text), 1);
+    }
+
+    #[test]
+    fn public_suffix() {
+        let url = Url::parse("https://www.example.co.uk/").unwrap();
+        assert!(Cookie::parse("a=1; Domain=co.uk", &url).is_none());
+        assert!(Cookie::parse("a=1; Domain=uk", &url).is_none());
+        let cookie = Cookie::parse("a=1; Domain=.Example.co.uk", &url).unwrap();
+        assert_eq!(cookie.domain, "example.co.uk");
+        assert!(!cookie.host_only);
+
+        // the host itself is a public suffix, the cookie is only for it
+        let url = Url::parse("https://github.io/").unwrap();
+        let cookie = Cookie::parse("a=1; Domain=github.io", &url).unwrap();
+        assert_eq!(cookie.domain, "github.io");
+        assert!(cookie.host_only);
+
+        let mut jar = CookieJar::default();
+        let text = ".co.uk\tTRUE\t/\tFALSE\t0\ta\t1\nexample.co.uk\tTRUE\t/\tFALSE\t0\tb\t2\n";
+        assert_eq!(jar.import_netscape(text), 1);
+    }
+
+    #[test]
+    fn sites() {
+        let site = |url: &str| site(&Url::parse(url).unwrap());
+        assert_eq!(site("https://a.b.example.co.uk/"), "example.co.uk");
+        assert_eq!(site("https://www.example.com/"), "example.com");
+        assert_eq!(site("https://user.github.io/"), "user.github.io");
+        assert_ne!(site("https://a.github.io/"), site("https://b.github.io/"));
+        assert_eq!(site("http://localhost:8080/"), "localhost");
+        assert_eq!(site("http://127.0.0.1/"), "127.0.0.1");
+    }
+
+    #[test]
+    fn invalid() {
+        for date in [
+            "",
+            "Sun, 06 Nov 1994 GMT",
+            "Sun, 06 1994 08:49:37 GMT",
+            "Sun, Nov 1994 08:49:37 GMT",
+            "Sun, 32 Nov 1994 08:49:37 GMT",
+            "Sun, 06 Nov 1994 24:00:00 GMT",
+            "Sun, 06 Nov 1994 08:60:00 GMT",
+            "Sun, 06 Nov 1600 08:49:37 GMT",
+        ] {
+            assert_eq!(seconds(date), None, "{}", date);
+        }
+    }
+}
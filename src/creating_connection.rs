@@ -1,233 +1,538 @@
 use std::{
     collections::HashMap,
-    io::{Read, Seek, SeekFrom, Write},
+    io::{Seek, SeekFrom, Write},
     net::TcpStream,
+    thread::JoinHandle,
+    time::Instant,
 };
 
+use rustls::ClientConnection;
 use url::Url;
 
 use muzzman_lib::prelude::*;
 
-use crate::{connection::Connection, error};
+use crate::{
+    auth,
+    changing_module::Handoff,
+    checksum,
+    chunked::ChunkedDecoder,
+    connection::Connection,
+    content_encoding::{self, ContentDecoder},
+    cookies,
+    downloading::Recived,
+    error, filename,
+    h2::{self, H2Connection, H2Stream},
+    pool::{self, PoolKey, Reusable},
+    proxy::{self, Proxy},
+    request::{host_header, RequestTarget},
+    response::{HeadReader, Headers, ObsFold, ParseOptions, ResponseHead},
+    retry::{self, Failure},
+    segments::Segments,
+    sse::EventStream,
+    timeouts::{self, get_timeouts, Timeouts},
+    tls::{self, TlsOptions},
+    websocket::{self, Framing, WebSocket},
+};
 
 pub fn creating_connection(element: &ERow, storage: &mut Storage) -> Result<(), SessionError> {
-    let Some(url) = element.read().unwrap().url.clone() else {
-        return Err(error(element, "No url"));
-    };
+    // a request that was started in a other tick is continued
+    if let Some(request) = storage.remove::<Request>() {
+        return connecting(element, storage, request);
+    }
 
-    let Ok(url) = Url::parse(&url)else{
-        return Err(error(element, "Cannot parse url"));
-    };
+    let url = get_url(element)?;
 
-    let method = get_method(element)?;
+    // after a 303 the method of the element is not used for this transfer
+    let switched_to_get = storage.get::<SwitchedToGet>().is_some();
+    let method = if switched_to_get {
+        "GET".to_string()
+    } else {
+        get_method(element)?
+    };
 
-    let port = get_port(element)?;
+    let port = get_port(element, &url)?;
 
-    let headers = get_headers(element);
+    let mut headers = get_headers(element);
 
-    let Ok(adresses) = url.socket_addrs(|| Some(port))else{
-        return Err(error(element, "Error: cannot resolv host, is probably a invalid url or your dns is blocking it!"));
-    };
+    // after a redirect to a other host the credentials of the user are not sent, like from github to s3
+    if storage
+        .get::<FirstOrigin>()
+        .is_some_and(|first| first.0 != url.origin())
+    {
+        headers.retain(|name, _| {
+            !CREDENTIAL_HEADERS
+                .iter()
+                .any(|credential| name.eq_ignore_ascii_case(credential))
+        });
+    }
 
-    let mut conn = None;
+    let compression = get_bool(element, "compression");
+    let save_raw = get_bool(element, "save-raw");
+    if compression
+        && !headers
+            .keys()
+            .any(|name| name.eq_ignore_ascii_case("Accept-Encoding"))
+    {
+        headers.insert(
+            "Accept-Encoding".to_string(),
+            content_encoding::ACCEPT_ENCODING.to_string(),
+        );
+    }
 
-    if port == 443 {
-        log::info!("Start connection on port 433 that means that should be tls");
-        log::info!("Try to create tls connection!");
-        let root_store = rustls::RootCertStore {
-            roots: webpki_roots::TLS_SERVER_ROOTS
-                .0
-                .iter()
-                .map(|e| {
-                    rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
-                        e.subject,
-                        e.spki,
-                        e.name_constraints,
-                    )
-                })
-                .collect(),
-        };
-        let config = rustls::client::ClientConfig::builder()
-            .with_safe_defaults()
-            .with_root_certificates(root_store)
-            .with_no_client_auth();
-
-        let server_name = if let Some(domain) = url.domain() {
-            if let Ok(server_name) = rustls::ServerName::try_from(domain) {
-                server_name
-            } else {
-                return Err(error(element, "Cannot resolv host name"));
-            }
-        } else if let Ok(server_name) =
-            rustls::ServerName::try_from(adresses[0].ip().to_string().as_ref())
+    let sse = get_bool(element, "sse");
+    if sse {
+        if !headers
+            .keys()
+            .any(|name| name.eq_ignore_ascii_case("Accept"))
         {
-            server_name
-        } else {
-            return Err(error(element, "Invaild url"));
-        };
+            headers.insert("Accept".to_string(), "text/event-stream".to_string());
+        }
+        headers.insert("Cache-Control".to_string(), "no-cache".to_string());
+        if let Some(id) = storage
+            .get::<EventStream>()
+            .and_then(|stream| stream.last_event_id.clone())
+        {
+            headers.insert("Last-Event-ID".to_string(), id);
+        }
+    }
 
-        log::info!("Tls setup success!");
+    // if something was already recived we are resuming
+    // a decoded body cannot be resumed from the middle, the server will send from 0
+    let resume_from = get_recv(element);
+    let was_decoded = !save_raw && get_response_header(element, "Content-Encoding").is_some();
+    // a event stream is continued with `Last-Event-ID` not with a range
+    if resume_from > 0 && !was_decoded && !sse {
+        headers.insert("Range".to_string(), format!("bytes={}-", resume_from));
+        if let Some(validator) = get_validator(element) {
+            headers.insert("If-Range".to_string(), validator);
+        }
+    }
 
-        if let Ok(connection) =
-            rustls::client::ClientConnection::new(std::sync::Arc::new(config), server_name)
-        {
-            let mut tcp = None;
-            for adress in adresses {
-                if let Ok(connection) = TcpStream::connect(adress) {
-                    tcp = Some(connection);
-                    break;
-                }
-            }
+    // for websocket the body is sent as messages after the handshake
+    let is_websocket = matches!(url.scheme(), "ws" | "wss");
+    let mut websocket_key = None;
+    let mut body_length = None;
+    if is_websocket {
+        websocket_key =
+            Some(websocket::handshake_headers(&mut headers).map_err(|err| error(element, err))?);
+    } else if let Some(length) = get_body_length(element).filter(|_| !switched_to_get) {
+        headers.insert("Content-Length".to_string(), length.to_string());
+        body_length = Some(length);
+    }
 
-            if let Some(tcp) = tcp {
-                log::info!("Tls Connected");
-                conn = Some(Connection::TLSClient(connection, tcp))
-            }
+    storage.remove::<ChunkedDecoder>();
+    storage.remove::<ContentDecoder>();
+    storage.remove::<Segments>();
+    storage.remove::<Reusable>();
+
+    let options = get_parse_options(element);
+
+    let connect_options = get_connect_options(element, &url, port)?;
+    connect_options.proxy_headers(&url, &mut headers);
+
+    let target = connect_options
+        .request_target(&url, get_request_target(element, &method)?)
+        .build(&url, port);
+
+    let host = get_host(element, &url, port, &headers);
+
+    auth::authorize(element, storage, &url, &method, &target, &mut headers)?;
+
+    cookies::add_cookie_header(element, storage, &url, &method, &mut headers);
+
+    let key = PoolKey::new(&url, port, &connect_options);
+
+    // a idle connection can be closed by the server at any time, if is not responding a new one is created
+    // websocket is never pooled, a idle connection is not taken to be dropped
+    let mut pooled = None;
+    let idle = if is_websocket { None } else { pool::take(&key) };
+    if let Some(mut conn) = idle {
+        log::info!("Using connection from pool");
+        let pinned = connect_options
+            .tls_options
+            .verify_pins(conn.peer_certificates().as_deref());
+        if let Err(err) = &pinned {
+            log::info!("Pooled connection is not used: {}", err);
         }
-    } else {
-        log::info!("Starting Tcp Connection");
-        for adress in adresses {
-            if let Ok(connection) = TcpStream::connect(adress) {
-                log::info!("Connection succesfuly!");
-                conn = Some(Connection::TCP(connection));
-                break;
-            }
+        if pinned.is_ok()
+            && send_request(&mut conn, &url, &method, &target, &host, &headers).is_ok()
+        {
+            pooled = Some(conn);
         }
     }
 
-    let Some(mut conn) = conn else{
-        return Err(error(element, "Error: cannot connect to host!"));
+    let stage = if pooled.is_some() {
+        Stage::Uploading
+    } else {
+        // a failed connection is retried, like a dns error or a reset
+        match open_connection(&url, port, &connect_options) {
+            Ok(opening) => Stage::Opening(Box::new(opening)),
+            Err(failure) => return retry::failed_with(element, storage, failure),
+        }
     };
 
-    let send = format!(
-        "{} {} HTTP/1.1\r\nHost: {}\r\n",
+    let request = Request {
+        url,
+        port,
         method,
-        url.path(),
-        url.domain().unwrap()
-    );
-    log::info!("Sending Request: {}", send);
-    let send = send.as_bytes();
-
-    let Ok(size) = conn.write(send) else{
-        return Err(error(element, "Error: Connection faild!"));
+        target,
+        host,
+        headers,
+        connect_options,
+        key,
+        options,
+        websocket_key,
+        resume_from,
+        save_raw,
+        sse,
+        body: body_length.is_some_and(|length| length > 0),
+        pooled: pooled.is_some(),
+        stage,
     };
 
-    if size != send.len() {
-        return Err(error(
-            element,
-            "Error: Cannot write to connection, that means that the url or port is invalid!",
-        ));
+    match pooled {
+        Some(conn) => {
+            storage.set(conn);
+            sent(element, storage, request)
+        }
+        None => connecting(element, storage, request),
     }
+}
 
-    log::info!("Headers: {:?}", headers);
+/// A request that is made a step every tick, the session is never blocked
+pub struct Request {
+    url: Url,
+    port: u16,
+    method: String,
+    target: String,
+    host: String,
+    headers: HashMap<String, String>,
+    connect_options: ConnectOptions,
+    key: PoolKey,
+    options: ParseOptions,
+    websocket_key: Option<String>,
+    resume_from: usize,
+    save_raw: bool,
+    sse: bool,
+    /// Has a body that `uploading` is sending
+    body: bool,
+    /// A pooled or shared connection that is closed is not a failure
+    /// the request is made again on a new one
+    pooled: bool,
+    stage: Stage,
+}
 
-    for header in headers {
-        let send = format!("{}: {}\r\n", header.0, header.1);
-        let send = send.as_bytes();
-        let Ok(_) = conn.write_all(send)else{
-            return Err(error(element, "Error: Connection faild!"));
-        };
-    }
+enum Stage {
+    Opening(Box<Opening>),
+    /// The request was sent, the body is sent before the response is readed
+    Uploading,
+    /// Since when is waiting for the response
+    Reading(HeadReader, Instant),
+}
 
-    if let Some(Type::FileOrData(ford)) = element.write().unwrap().element_data.get_mut("body") {
-        let send = format!("Content-Length: {}", {
-            if let Ok(cur) = ford.seek(SeekFrom::Current(0)) {
-                let res = ford.seek(SeekFrom::End(0)).unwrap();
-                ford.seek(SeekFrom::Start(cur)).unwrap();
-                res
-            } else {
-                0
+fn connecting(
+    element: &ERow,
+    storage: &mut Storage,
+    mut request: Request,
+) -> Result<(), SessionError> {
+    match &mut request.stage {
+        Stage::Opening(opening) => {
+            let mut conn = match opening.poll() {
+                Ok(Some(conn)) => conn,
+                Ok(None) => {
+                    storage.set(request);
+                    return Ok(());
+                }
+                Err(failure) => return retry::failed_with(element, storage, failure),
+            };
+            request.pooled = opening.shared;
+
+            if let Err(err) = send_request(
+                &mut conn,
+                &request.url,
+                &request.method,
+                &request.target,
+                &request.host,
+                &request.headers,
+            ) {
+                if !can_reconnect(&request, &conn) {
+                    return retry::failed(element, storage, err, None);
+                }
+                // nothing was sent, a new connection is opened without a attempt
+                log::info!("Shared connection was closed: {}", err);
+                drop(conn);
+                match open_connection(&request.url, request.port, &request.connect_options) {
+                    Ok(opening) => request.stage = Stage::Opening(Box::new(opening)),
+                    Err(failure) => return retry::failed_with(element, storage, failure),
+                }
+                storage.set(request);
+                return Ok(());
             }
-        });
-        log::info!("Add header: {}", send);
-        let res = conn.write_all(send.as_bytes());
+            storage.set(conn);
+            sent(element, storage, request)
+        }
+        // `uploading` sent the body, now is the response
+        Stage::Uploading => {
+            request.stage = Stage::Reading(HeadReader::default(), Instant::now());
+            reading_response(element, storage, request)
+        }
+        Stage::Reading(..) => reading_response(element, storage, request),
+    }
+}
 
-        if let Err(err) = res {
-            log::error!("Cannot Send Contelt-Length header!");
-            return Err(error(element, err.to_string()));
+/// A idle connection from the pool or a HTTP/2 connection of a other element that was closed
+/// a HTTP/2 stream can fail on a connection that is still usable, that is a real failure
+fn can_reconnect(request: &Request, conn: &Connection) -> bool {
+    request.pooled && !matches!(conn, Connection::H2(stream) if stream.is_usable())
+}
+
+/// The headers were sent, `uploading` will come back when the body is sent
+fn sent(element: &ERow, storage: &mut Storage, mut request: Request) -> Result<(), SessionError> {
+    if request.body {
+        timeouts::started(storage);
+        request.stage = Stage::Uploading;
+        storage.set(request);
+        element.set_status(4);
+        return Ok(());
+    }
+
+    request.stage = Stage::Reading(HeadReader::default(), Instant::now());
+    reading_response(element, storage, request)
+}
+
+fn reading_response(
+    element: &ERow,
+    storage: &mut Storage,
+    mut request: Request,
+) -> Result<(), SessionError> {
+    let Some(mut conn) = storage.remove::<Connection>() else {
+        element.set_status(1);
+        return Ok(());
+    };
+    let Stage::Reading(head, since) = &mut request.stage else {
+        element.set_status(1);
+        return Ok(());
+    };
+
+    let timeout = request.connect_options.timeouts.read;
+    let response = match read_response(&mut conn, head, &request.options) {
+        Ok(Some(response)) => Ok(response),
+        Ok(None) if timeout.is_some_and(|timeout| since.elapsed() >= timeout) => {
+            Err("Error: Timed out waiting for the response headers".to_string())
+        }
+        Ok(None) => {
+            storage.set(conn);
+            storage.set(request);
+            return Ok(());
+        }
+        Err(err) => Err(err),
+    };
+
+    let response = match response {
+        Ok(response) => response,
+        Err(err) if can_reconnect(&request, &conn) => {
+            log::info!("Pooled connection was closed: {}", err);
+            let mut element_w = element.write().unwrap();
+            if let Some(Type::FileOrData(body)) = element_w.element_data.get_mut("body") {
+                let _ = body.seek(SeekFrom::Start(0));
+            }
+            element_w.settings.set("sent", Type::USize(0));
+            drop(element_w);
+            element.set_status(1);
+            return Ok(());
         }
+        Err(err) => return retry::failed(element, storage, err, None),
+    };
+
+    let Request {
+        url,
+        port,
+        method,
+        host,
+        connect_options,
+        key,
+        websocket_key,
+        resume_from,
+        save_raw,
+        sse,
+        ..
+    } = request;
+
+    {
+        let peer_certificates = conn
+            .peer_certificates()
+            .map(|certs| Type::HashMapSS(tls::describe_chain(&certs)))
+            .unwrap_or(Type::None);
+        element
+            .write()
+            .unwrap()
+            .settings
+            .set("peer-certificates", peer_certificates);
     }
-    conn.write_all(b"\r\n\r\n").unwrap();
-    log::info!("Response beagin reading");
 
     {
-        let mut bytes = [0; 1];
+        let ResponseHead {
+            version,
+            status,
+            reason: status_str,
+            headers,
+        } = response;
 
-        let mut r: u8 = 0;
-        let mut n: u8 = 0;
+        log::info!("Status: {} {} {}", version, status, status_str);
+        log::info!("Response Headers: {:?}", headers);
 
-        let mut count = 0;
+        // also the redirects and the 401 can set cookies
+        cookies::store(element, &url, &headers);
 
-        let mut buffer = String::new();
+        if matches!(status, 301 | 302 | 303 | 307 | 308) {
+            return redirect(element, storage, &url, status, &headers, &method);
+        }
 
-        let mut status: u16 = 0;
-        let mut status_str: String = String::new();
-        let mut headers = HashMap::new();
+        if status == 401 {
+            return auth::unauthorized(element, storage, &url, &status_str, &headers);
+        }
 
-        loop {
-            if r == 2 && n == 2 {
-                break;
+        if retry::is_retryable_status(element, status) {
+            return retry::failed(
+                element,
+                storage,
+                format!("Http Error: status: {} {}", status, status_str),
+                retry::retry_after(&headers),
+            );
+        }
+
+        // the server answered, the next failure has again all the attempts
+        retry::reset(storage);
+
+        if let Some(key) = websocket_key {
+            if status != 101 {
+                return Err(error(
+                    element,
+                    format!("Error: WebSocket handshake: {} {}", status, status_str),
+                ));
             }
-            match conn.read(&mut bytes) {
-                Ok(read) => {
-                    if read == 1 {
-                        match bytes[0] {
-                            b'\r' => r += 1,
-                            b'\n' => n += 1,
-                            _ => {
-                                if r > 0 && n > 0 {
-                                    match count {
-                                        0 => {
-                                            let spaces = buffer.split(' ').collect::<Vec<&str>>();
-                                            status = spaces[1].parse().unwrap();
-                                            let mut data = String::new();
-                                            for s in spaces[2..].iter() {
-                                                data.push_str(s);
-                                                data.push(' ');
-                                            }
-                                            status_str = data;
-                                        }
-                                        _ => {
-                                            let map = buffer.split(':').collect::<Vec<&str>>();
-                                            headers.insert(map[0].to_owned(), map[1].to_owned());
-                                        }
-                                    }
-                                    count += 1;
-                                    buffer.clear();
-                                }
-                                buffer.push(bytes[0] as char);
+            websocket::verify_accept(&key, &headers).map_err(|err| error(element, err))?;
+            log::info!("WebSocket Connected");
 
-                                r = 0;
-                                n = 0;
-                            }
-                        }
-                    } else {
-                        break;
-                    }
-                }
+            let (framing, text) = get_websocket_options(element);
+            storage.set(WebSocket::new(conn, framing, text));
+            element.set_status(3);
+            return Ok(());
+        }
 
-                Err(err) => match err.kind() {
-                    std::io::ErrorKind::WouldBlock => {}
-                    _ => {
-                        return Err(error(element, format!("Error: {:?}", err)));
-                    }
-                },
+        if status == 101 {
+            // a other module can use only a std socket, the tls session cannot be given
+            let Connection::TCP(tcp, _) = conn else {
+                return Err(error(
+                    element,
+                    format!(
+                        "Error: {} {} over TLS cannot be given to a other module",
+                        status, status_str
+                    ),
+                ));
+            };
+            storage.set(tcp);
+            storage.set(Handoff::switching_protocols(&headers));
+            element.set_status(2);
+            return Ok(());
+        }
+
+        if sse {
+            // 204 is how the server says to stop reconnecting
+            if status == 204 {
+                log::info!("Event stream ended by the server");
+                storage.remove::<EventStream>();
+                element.set_status(8);
+                return Ok(());
+            }
+
+            let is_event_stream = headers
+                .get("Content-Type")
+                .and_then(|content_type| content_type.split(';').next())
+                .map(|mime| mime.trim().eq_ignore_ascii_case("text/event-stream"))
+                .unwrap_or(false);
+            if status != 200 || !is_event_stream {
+                return Err(error(
+                    element,
+                    format!(
+                        "Error: Not a event stream: {} {} {:?}",
+                        status,
+                        status_str,
+                        headers.get("Content-Type")
+                    ),
+                ));
+            }
+
+            match storage.get_mut::<EventStream>() {
+                Some(stream) => stream.connected(),
+                None => {
+                    storage.set(EventStream::default());
+                }
             }
         }
 
-        log::info!("Status: {} {}", status, status_str);
-        log::info!("Response Headers: {:?}", headers);
+        let partial = status == 206 && resume_from > 0 && !sse;
+
+        // there is nothing after the headers, even if has Content-Length
+        let no_body = method == "HEAD" || matches!(status, 204 | 304);
 
-        if status != 200 {
+        if status != 200 && !partial && !no_body {
             return Err(error(
                 element,
                 format!("Http Error: status: {} {}", status, status_str),
             ));
         }
 
+        let mut total_length = None;
+        if partial {
+            let Some((start, end, total)) =
+                headers.get("Content-Range").and_then(parse_content_range)
+            else {
+                return Err(error(
+                    element,
+                    "Error: 206 Partial Content without a valid Content-Range",
+                ));
+            };
+
+            if start != resume_from {
+                return Err(error(
+                    element,
+                    format!(
+                        "Error: Asked range from {} but server sent from {}",
+                        resume_from, start
+                    ),
+                ));
+            }
+
+            log::info!("Resuming from {}", start);
+            total_length = Some(total.unwrap_or(end + 1));
+            let _ = element
+                .write()
+                .unwrap()
+                .data
+                .seek(SeekFrom::Start(start as u64));
+        } else if resume_from > 0 && !sse {
+            // the server ignored the range or the file was changed, start over
+            log::warn!("Server ignored the range, restarting from 0");
+            let mut element = element.write().unwrap();
+            element.settings.set("recv", Type::USize(0));
+            element.progress = 0.0;
+            // the old tail is not from this response
+            truncate_data(&mut element.data);
+        }
+
+        let chunked = !no_body && headers.contains_token("Transfer-Encoding", "chunked");
+
         let content_length;
-        if let Some(data) = headers.get("Content-Length") {
-            if let Ok(cl) = data.trim().parse::<usize>() {
+        if no_body {
+            content_length = 0;
+        } else if chunked {
+            // when is chunked Content-Length should be ignored, the end is the last chunk
+            log::info!("Transfer-Encoding is chunked");
+            content_length = usize::MAX;
+            storage.set(ChunkedDecoder::default());
+        } else if let Some(data) = headers.get("Content-Length") {
+            if let Ok(cl) = data.parse::<usize>() {
                 content_length = cl;
             } else {
                 return Err(error(element, "Error: Cannot parse Content-Length"));
@@ -237,6 +542,26 @@ pub fn creating_connection(element: &ERow, storage: &mut Storage) -> Result<(),
             content_length = usize::MAX;
         }
 
+        let content_length = total_length.unwrap_or(content_length);
+        storage.set(Recived(if partial { resume_from } else { 0 }));
+
+        let mut encoded = false;
+        if let Some(content_encoding) = headers.get("Content-Encoding") {
+            if save_raw {
+                log::info!("Content-Encoding: {}, saving raw", content_encoding);
+            } else {
+                match ContentDecoder::new(content_encoding) {
+                    Ok(Some(decoder)) => {
+                        log::info!("Content-Encoding: {}", content_encoding);
+                        encoded = true;
+                        storage.set(decoder);
+                    }
+                    Ok(None) => {}
+                    Err(err) => return Err(error(element, format!("Error: {}", err))),
+                }
+            }
+        }
+
         log::info!("Content-Length set to {}", content_length);
 
         {
@@ -244,15 +569,781 @@ pub fn creating_connection(element: &ERow, storage: &mut Storage) -> Result<(),
             element
                 .element_data
                 .set("download-content-length", Type::USize(content_length));
-            element.settings.set("headers", Type::HashMapSS(headers));
+            element
+                .settings
+                .set("headers", Type::HashMapSS(headers.to_map()));
+        }
+
+        if !sse && get_bool(element, "rename") {
+            filename::rename(element, &url, &headers);
+        }
+
+        if !sse && !no_body {
+            checksum::start(
+                element,
+                storage,
+                &headers,
+                partial.then_some(resume_from),
+                encoded,
+            )?;
+        }
+
+        // HTTP/2 streams are not pooled, the connection is already shared
+        let is_h2 = matches!(conn, Connection::H2(_));
+        if let Some(keep_alive) = pool::keep_alive(&headers).filter(|_| !is_h2) {
+            // without a length the end of the body is when the connection is closed
+            if chunked || content_length != usize::MAX {
+                storage.set(Reusable { key, keep_alive });
+            }
+        }
+
+        // if no other module wants it, `changing_module` will continue the download
+        if let Some(handoff) = Handoff::from_content_type(&headers) {
+            storage.set(conn);
+            storage.set(handoff);
+            element.set_status(2);
+            return Ok(());
+        }
+
+        let accept_ranges = headers.contains_token("Accept-Ranges", "bytes");
+
+        let connections = get_connections(element);
+        if connections > 1
+            && accept_ranges
+            && !chunked
+            && !partial
+            && !encoded
+            && content_length != usize::MAX
+            && method == "GET"
+            && !sse
+        {
+            log::info!("Segmented download with {} connections", connections);
+            storage.remove::<Reusable>();
+            timeouts::started(storage);
+            storage.set(Segments::new(
+                url,
+                port,
+                host,
+                connect_options,
+                conn,
+                content_length,
+                connections,
+            ));
+            checksum::not_incremental(storage);
+            element.set_status(3);
+            return Ok(());
         }
     }
 
+    timeouts::started(storage);
     storage.set(conn);
-    element.set_status(4);
+    element.set_status(3);
+    Ok(())
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash, Debug)]
+pub enum TlsMode {
+    /// From the url scheme, `https` and `wss`
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl TlsMode {
+    pub fn use_tls(&self, url: &Url) -> bool {
+        match self {
+            TlsMode::Auto => matches!(url.scheme(), "https" | "wss"),
+            TlsMode::Always => true,
+            TlsMode::Never => false,
+        }
+    }
+}
+
+/// How to connect, the same for every connection of a element
+#[derive(Clone, Default, Debug)]
+pub struct ConnectOptions {
+    /// The name sent in SNI and verified in the certificate, if none is from the url
+    pub sni: Option<String>,
+    pub tls: TlsMode,
+    pub tls_options: TlsOptions,
+    pub proxy: Option<Proxy>,
+    pub timeouts: Timeouts,
+}
+
+impl ConnectOptions {
+    /// Thru a proxy TLS and websocket are in a CONNECT tunnel
+    pub fn tunnel(&self, url: &Url) -> bool {
+        self.tls.use_tls(url) || matches!(url.scheme(), "ws" | "wss")
+    }
+
+    /// The proxy that gets the request, it needs the absolute-form and `Proxy-Authorization`
+    pub fn forward_proxy(&self, url: &Url) -> Option<&Proxy> {
+        self.proxy
+            .as_ref()
+            .filter(|proxy| proxy.is_http() && !self.tunnel(url))
+    }
+
+    /// The origin-form is changed to absolute-form when is sent to a proxy
+    pub fn request_target(&self, url: &Url, target: RequestTarget) -> RequestTarget {
+        if target == RequestTarget::Origin && self.forward_proxy(url).is_some() {
+            RequestTarget::Absolute
+        } else {
+            target
+        }
+    }
+
+    pub fn proxy_headers(&self, url: &Url, headers: &mut HashMap<String, String>) {
+        if let Some(authorization) = self
+            .forward_proxy(url)
+            .and_then(|proxy| proxy.authorization())
+        {
+            headers.insert("Proxy-Authorization".to_string(), authorization);
+        }
+    }
+}
+
+/// A tcp stream to the host, directly or thru the proxy
+fn connect(url: &Url, port: u16, options: &ConnectOptions) -> Result<TcpStream, Failure> {
+    if let Some(proxy) = &options.proxy {
+        log::info!(
+            "Connecting thru proxy: {}",
+            proxy.url.host_str().unwrap_or_default()
+        );
+        return proxy.connect(url, port, options.tunnel(url), &options.timeouts);
+    }
+
+    let Ok(adresses) = url.socket_addrs(|| Some(port)) else {
+        return Err(Failure::Transient(
+            "Error: cannot resolv host, is probably a invalid url or your dns is blocking it!"
+                .to_string(),
+        ));
+    };
+
+    log::info!("Starting Tcp Connection");
+    if let Some(connection) = options.timeouts.connect(adresses) {
+        log::info!("Connection succesfuly!");
+        return Ok(connection);
+    }
+
+    Err(Failure::Transient(
+        "Error: cannot connect to host!".to_string(),
+    ))
+}
+
+/// Starts to connect to the host or the proxy, if the scheme is `https` will be tls
+/// if the server accepts `h2` the connection is shared and a new stream is used
+/// a invalid config, certificate or pin is permanent, the next attempt will fail the same
+pub fn open_connection(url: &Url, port: u16, options: &ConnectOptions) -> Result<Opening, Failure> {
+    // websocket is only over HTTP/1.1
+    let allow_h2 = !matches!(url.scheme(), "ws" | "wss");
+
+    let key = PoolKey::new(url, port, options);
+    if let Some(shared) = h2::get(&key).filter(|_| allow_h2) {
+        log::info!("Using HTTP/2 connection");
+        let stream = H2Stream::new(shared);
+        options
+            .tls_options
+            .verify_pins(stream.peer_certificates().as_deref())
+            .map_err(Failure::Permanent)?;
+        return Ok(Opening {
+            step: Step::Ready(Connection::H2(stream)),
+            key,
+            options: options.clone(),
+            shared: true,
+        });
+    }
+
+    let mut tls = None;
+    if options.tls.use_tls(url) {
+        log::info!("Try to create tls connection on port {}!", port);
+        let alpn_protocols = if allow_h2 {
+            vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+        } else {
+            vec![b"http/1.1".to_vec()]
+        };
+        let config = options
+            .tls_options
+            .client_config(alpn_protocols)
+            .map_err(Failure::Permanent)?;
+
+        // a IP is also accepted, thru a proxy the host is not resolved by us
+        let name = match &options.sni {
+            Some(sni) => sni.clone(),
+            None => url
+                .host_str()
+                .unwrap_or_default()
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_string(),
+        };
+        let Ok(server_name) = rustls::ServerName::try_from(name.as_str()) else {
+            return Err(Failure::Permanent(format!("Invalid server name: {}", name)));
+        };
+
+        log::info!("Tls setup success!");
+
+        tls = Some(
+            ClientConnection::new(std::sync::Arc::new(config), server_name)
+                .map_err(|err| Failure::Permanent(format!("Error: Tls: {}", err)))?,
+        );
+    }
+
+    // std cannot resolv or connect without blocking, this is done by a thread
+    let thread = {
+        let (url, options) = (url.clone(), options.clone());
+        std::thread::spawn(move || connect(&url, port, &options))
+    };
+
+    Ok(Opening {
+        step: Step::Connecting(thread, tls),
+        key,
+        options: options.clone(),
+        shared: false,
+    })
+}
+
+/// A connection that is opened a step every tick
+pub struct Opening {
+    step: Step,
+    key: PoolKey,
+    options: ConnectOptions,
+    /// A HTTP/2 connection that was opened by a other element, can be closed from when was checked
+    shared: bool,
+}
+
+enum Step {
+    /// A HTTP/2 connection that was already open
+    Ready(Connection),
+    /// The dns, the tcp connection and the proxy, the tls is after
+    Connecting(
+        JoinHandle<Result<TcpStream, Failure>>,
+        Option<ClientConnection>,
+    ),
+    /// Since when the tls handshake started
+    Handshaking(ClientConnection, TcpStream, Instant),
+    Done,
+}
+
+impl Opening {
+    /// None while is not connected, the connection is non-blocking
+    pub fn poll(&mut self) -> Result<Option<Connection>, Failure> {
+        loop {
+            match std::mem::replace(&mut self.step, Step::Done) {
+                Step::Ready(conn) => return Ok(Some(conn)),
+                Step::Connecting(thread, tls) => {
+                    if !thread.is_finished() {
+                        self.step = Step::Connecting(thread, tls);
+                        return Ok(None);
+                    }
+
+                    let tcp = thread.join().unwrap_or_else(|_| {
+                        Err("Error: cannot connect to host!".to_string().into())
+                    })?;
+                    tcp.set_nonblocking(true)
+                        .map_err(|err| format!("Error: {}", err))?;
+
+                    match tls {
+                        Some(tls) => self.step = Step::Handshaking(tls, tcp, Instant::now()),
+                        None => return Ok(Some(Connection::TCP(tcp, Vec::new()))),
+                    }
+                }
+                Step::Handshaking(mut tls, mut tcp, since) => {
+                    // the handshake is needed to know the alpn protocol
+                    while tls.is_handshaking() {
+                        match tls.complete_io(&mut tcp) {
+                            Ok(_) => {}
+                            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
+                                if self
+                                    .options
+                                    .timeouts
+                                    .read
+                                    .is_some_and(|timeout| since.elapsed() >= timeout)
+                                {
+                                    return Err("Error: Tls handshake: timed out"
+                                        .to_string()
+                                        .into());
+                                }
+                                self.step = Step::Handshaking(tls, tcp, since);
+                                return Ok(None);
+                            }
+                            Err(err) => return Err(handshake_failure(err)),
+                        }
+                    }
+                    return self.handshaked(tls, tcp).map(Some);
+                }
+                Step::Done => {
+                    return Err("Error: the connection was already opened"
+                        .to_string()
+                        .into())
+                }
+            }
+        }
+    }
+
+    fn handshaked(&self, mut tls: ClientConnection, tcp: TcpStream) -> Result<Connection, Failure> {
+        log::info!("Tls Connected");
+        // what the socket does not take is buffered, is sent on the next read or write
+        tls.set_buffer_limit(None);
+
+        self.options
+            .tls_options
+            .verify_pins(tls.peer_certificates())
+            .map_err(Failure::Permanent)?;
+
+        if tls.alpn_protocol() == Some(b"h2") {
+            log::info!("Server accepted HTTP/2");
+            let h2 =
+                H2Connection::new(tls, tcp).map_err(|err| format!("Error: HTTP/2: {}", err))?;
+            let shared = h2::register(self.key.clone(), h2);
+            return Ok(Connection::H2(H2Stream::new(shared)));
+        }
+
+        Ok(Connection::TLSClient(tls, tcp))
+    }
+}
+
+/// A certificate that is not valid will be the same on the next attempt, a reset is not
+fn handshake_failure(err: std::io::Error) -> Failure {
+    let reason = format!("Error: Tls handshake: {}", err);
+    match err
+        .get_ref()
+        .and_then(|err| err.downcast_ref::<rustls::Error>())
+    {
+        Some(
+            rustls::Error::InvalidCertificateEncoding
+            | rustls::Error::InvalidCertificateSignatureType
+            | rustls::Error::InvalidCertificateSignature
+            | rustls::Error::InvalidCertificateData(_)
+            | rustls::Error::NoCertificatesPresented
+            | rustls::Error::UnsupportedNameType,
+        ) => Failure::Permanent(reason),
+        _ => Failure::Transient(reason),
+    }
+}
+
+pub fn send_request(
+    conn: &mut Connection,
+    url: &Url,
+    method: &str,
+    target: &str,
+    host: &str,
+    headers: &HashMap<String, String>,
+) -> Result<(), String> {
+    if let Connection::H2(stream) = conn {
+        return stream
+            .send_request(url, method, target, host, headers)
+            .map_err(|err| format!("Error: HTTP/2: {}", err));
+    }
+
+    let mut send = format!("{} {} HTTP/1.1\r\nHost: {}\r\n", method, target, host);
+    log::info!("Sending Request: {}", send);
+    log::info!("Headers: {:?}", headers);
+
+    for header in headers {
+        if header.0.eq_ignore_ascii_case("Host") {
+            continue;
+        }
+        send.push_str(&format!("{}: {}\r\n", header.0, header.1));
+    }
+    send.push_str("\r\n");
+
+    if conn.write_all(send.as_bytes()).is_err() {
+        return Err("Error: Connection faild!".to_string());
+    }
+
     Ok(())
 }
 
+/// Reads the status line and the headers, after this the connection is at the start of the body
+/// the 1xx responses are skiped, None if the head was not recived yet
+pub fn read_response(
+    conn: &mut Connection,
+    head: &mut HeadReader,
+    options: &ParseOptions,
+) -> Result<Option<ResponseHead>, String> {
+    if let Connection::H2(stream) = conn {
+        return match stream.read_response() {
+            Ok(response) => Ok(Some(response)),
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => Ok(None),
+            Err(err) => Err(format!("Error: HTTP/2: {}", err)),
+        };
+    }
+
+    loop {
+        let Some(response) = head
+            .read(conn, options)
+            .map_err(|err| format!("Error: {}", err))?
+        else {
+            return Ok(None);
+        };
+        if (100..200).contains(&response.status) && response.status != 101 {
+            log::info!("Skiping: {} {}", response.status, response.reason);
+            continue;
+        }
+        return Ok(Some(response));
+    }
+}
+
+/// Empties the data, when the download is started over
+fn truncate_data(data: &mut FileOrData) {
+    let _ = data.seek(SeekFrom::Start(0));
+    match data {
+        FileOrData::File(_, Some(file)) => {
+            let _ = file.set_len(0);
+        }
+        FileOrData::File(_, None) => {}
+        FileOrData::Bytes(bytes) => *bytes = Default::default(),
+    }
+}
+
+/// Parses `bytes <start>-<end>/<total>`, total can be `*` when is unknown
+pub fn parse_content_range(value: &str) -> Option<(usize, usize, Option<usize>)> {
+    let range = value.trim().strip_prefix("bytes")?.trim();
+    let (range, total) = range.split_once('/')?;
+    let (start, end) = range.split_once('-')?;
+    let total = match total.trim() {
+        "*" => None,
+        total => Some(total.parse().ok()?),
+    };
+    Some((start.trim().parse().ok()?, end.trim().parse().ok()?, total))
+}
+
+/// The headers from the user that are only for the first origin
+const CREDENTIAL_HEADERS: [&str; 3] = ["Authorization", "Cookie", "Proxy-Authorization"];
+
+/// The origin before the first redirect
+pub struct FirstOrigin(pub url::Origin);
+
+/// After a 303, or a 301/302 for POST, the next requests are GET without the body
+/// is only for this transfer, the `method` and the `body` of the element are not changed
+pub struct SwitchedToGet;
+
+/// Follows a 3xx response, the next tick will connect to the new url
+fn redirect(
+    element: &ERow,
+    storage: &mut Storage,
+    url: &Url,
+    status: u16,
+    headers: &Headers,
+    method: &str,
+) -> Result<(), SessionError> {
+    let Some(location) = headers.get("Location") else {
+        return Err(error(
+            element,
+            format!("Http Error: status: {} without Location", status),
+        ));
+    };
+
+    let Ok(next) = url.join(location) else {
+        return Err(error(
+            element,
+            format!("Error: Cannot parse redirect location: {}", location),
+        ));
+    };
+
+    // like `magnet:` should be handled by a other module
+    if !matches!(next.scheme(), "http" | "https") {
+        log::info!("Redirect to {}", next);
+        element.write().unwrap().url = Some(next.to_string());
+        storage.set(Handoff::redirect(next.scheme()));
+        element.set_status(2);
+        return Ok(());
+    }
+
+    let max_redirects = get_max_redirects(element);
+
+    if storage.get::<FirstOrigin>().is_none() {
+        storage.set(FirstOrigin(url.origin()));
+    }
+
+    {
+        let mut element_w = element.write().unwrap();
+
+        let mut redirects = 0;
+        if let Some(Type::USize(r)) = element_w.settings.get("redirects") {
+            redirects = *r;
+        }
+
+        if redirects >= max_redirects {
+            drop(element_w);
+            return Err(error(
+                element,
+                format!(
+                    "Error: Too many redirects, max-redirects is {}",
+                    max_redirects
+                ),
+            ));
+        }
+
+        element_w
+            .settings
+            .set("redirects", Type::USize(redirects + 1));
+
+        // 303 is always GET, and for 301/302 every client is doing the same for POST
+        let switch_to_get = (status == 303 && method != "HEAD")
+            || (matches!(status, 301 | 302) && method == "POST");
+
+        if switch_to_get {
+            storage.set(SwitchedToGet);
+        } else if let Some(Type::FileOrData(body)) = element_w.element_data.get_mut("body") {
+            // 307 and 308 should send the same body again
+            let _ = body.seek(SeekFrom::Start(0));
+        }
+
+        element_w.settings.set("sent", Type::USize(0));
+        element_w.url = Some(next.to_string());
+    }
+
+    log::info!("Redirect: {} to {}", status, next);
+    element.set_status(1);
+    Ok(())
+}
+
+pub fn get_max_redirects(element: &ERow) -> usize {
+    if let Some(Type::USize(max)) = element.read().unwrap().element_data.get("max-redirects") {
+        *max
+    } else {
+        0
+    }
+}
+
+pub fn get_url(element: &ERow) -> Result<Url, SessionError> {
+    let Some(url) = element.read().unwrap().url.clone() else {
+        return Err(error(element, "No url"));
+    };
+
+    let Ok(url) = Url::parse(&url) else {
+        return Err(error(element, "Cannot parse url"));
+    };
+
+    Ok(url)
+}
+
+/// The length of `body` if has one
+pub fn get_body_length(element: &ERow) -> Option<u64> {
+    if let Some(Type::FileOrData(ford)) = element.write().unwrap().element_data.get_mut("body") {
+        if let Ok(cur) = ford.stream_position() {
+            let res = ford.seek(SeekFrom::End(0)).unwrap();
+            ford.seek(SeekFrom::Start(cur)).unwrap();
+            Some(res)
+        } else {
+            Some(0)
+        }
+    } else {
+        None
+    }
+}
+
+/// The `host` setting, the `Host` from `headers` or from the url
+pub fn get_host(element: &ERow, url: &Url, port: u16, headers: &HashMap<String, String>) -> String {
+    if let Some(Type::String(host)) = element.read().unwrap().element_data.get("host") {
+        return host.clone();
+    }
+
+    if let Some((_, host)) = headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("Host"))
+    {
+        return host.clone();
+    }
+
+    host_header(url, port)
+}
+
+/// The element `proxy` is first, then the module `proxy` and then the environment
+/// `direct` is for not using a proxy, `NO_PROXY` is for all
+pub fn get_connect_options(
+    element: &ERow,
+    url: &Url,
+    port: u16,
+) -> Result<ConnectOptions, SessionError> {
+    let mut options = ConnectOptions::default();
+    let proxy = get_string(element, "proxy");
+
+    if !proxy::no_proxy(url, port) {
+        options.proxy = match proxy {
+            Some(proxy) if proxy.trim().is_empty() || proxy.trim() == "direct" => None,
+            Some(proxy) => {
+                Some(Proxy::new(&proxy).map_err(|err| error(element, format!("Error: {}", err)))?)
+            }
+            None => Proxy::from_env(url),
+        };
+    }
+
+    // the CA files from the module and from the element are all used
+    {
+        let element = element.read().unwrap();
+        for values in [&element.settings, &element.element_data] {
+            if let Some(Type::String(files)) = values.get("ca-file") {
+                options.tls_options.ca_files.extend(
+                    files
+                        .split(';')
+                        .map(|file| file.trim().to_string())
+                        .filter(|file| !file.is_empty()),
+                );
+            }
+        }
+    }
+
+    options.timeouts = get_timeouts(element);
+
+    options.tls_options.system_certs = get_bool_any(element, "system-certs");
+    options.tls_options.insecure_skip_verify = get_bool_any(element, "insecure-skip-verify");
+    if let Some(pins) = get_string(element, "pinned-spki") {
+        options.tls_options.pinned_spki = tls::parse_pins(&pins);
+    }
+
+    match (
+        get_string(element, "client-cert"),
+        get_string(element, "client-key"),
+    ) {
+        (Some(cert), Some(key)) => options.tls_options.client_cert = Some((cert, key)),
+        (None, None) => {}
+        _ => {
+            return Err(error(
+                element,
+                "Error: client-cert and client-key should be set together",
+            ))
+        }
+    }
+
+    let element = element.read().unwrap();
+
+    if let Some(Type::String(sni)) = element.element_data.get("sni") {
+        options.sni = Some(sni.clone());
+    }
+
+    if let Some(Type::CustomEnum(tls)) = element.element_data.get("tls") {
+        options.tls = match tls.get_active().as_deref() {
+            Some("always") => TlsMode::Always,
+            Some("never") => TlsMode::Never,
+            _ => TlsMode::Auto,
+        };
+    }
+
+    Ok(options)
+}
+
+pub fn get_request_target(element: &ERow, method: &str) -> Result<RequestTarget, SessionError> {
+    let mut name = String::from("origin");
+    if let Some(Type::CustomEnum(target)) =
+        element.read().unwrap().element_data.get("request-target")
+    {
+        if let Some(active) = target.get_active() {
+            name = active;
+        }
+    }
+
+    RequestTarget::new(&name, method).map_err(|err| error(element, format!("Error: {}", err)))
+}
+
+pub fn get_parse_options(element: &ERow) -> ParseOptions {
+    let mut options = ParseOptions::default();
+    let element = element.read().unwrap();
+
+    if let Some(Type::USize(max_size)) = element.element_data.get("max-header-size") {
+        options.max_size = *max_size;
+    }
+
+    if let Some(Type::CustomEnum(obs_fold)) = element.element_data.get("obs-fold") {
+        if let Some(obs_fold) = obs_fold.get_active() {
+            options.obs_fold = match obs_fold.as_str() {
+                "reject" => ObsFold::Reject,
+                _ => ObsFold::Replace,
+            };
+        }
+    }
+
+    options
+}
+
+/// How the messages are writen in the data and if the body is sent as text
+pub fn get_websocket_options(element: &ERow) -> (Framing, bool) {
+    let mut framing = Framing::Append;
+    if let Some(Type::CustomEnum(value)) = element
+        .read()
+        .unwrap()
+        .element_data
+        .get("websocket-framing")
+    {
+        if value.get_active().as_deref() == Some("length-prefixed") {
+            framing = Framing::LengthPrefixed;
+        }
+    }
+
+    (framing, get_bool(element, "websocket-text"))
+}
+
+/// From the element and if is not set from the module settings
+pub fn get_string(element: &ERow, name: &str) -> Option<String> {
+    let element = element.read().unwrap();
+    if let Some(Type::String(value)) = element.element_data.get(name) {
+        return Some(value.clone());
+    }
+    if let Some(Type::String(value)) = element.settings.get(name) {
+        return Some(value.clone());
+    }
+    None
+}
+
+/// True if is enabled in the element or in the module settings
+pub fn get_bool_any(element: &ERow, name: &str) -> bool {
+    let element = element.read().unwrap();
+    [&element.element_data, &element.settings]
+        .iter()
+        .any(|values| matches!(values.get(name), Some(Type::Bool(true))))
+}
+
+pub fn get_bool(element: &ERow, name: &str) -> bool {
+    matches!(
+        element.read().unwrap().element_data.get(name),
+        Some(Type::Bool(true))
+    )
+}
+
+pub fn get_connections(element: &ERow) -> usize {
+    if let Some(Type::USize(connections)) = element.read().unwrap().element_data.get("connections")
+    {
+        *connections
+    } else {
+        1
+    }
+}
+
+pub fn get_recv(element: &ERow) -> usize {
+    if let Some(Type::USize(recv)) = element.read().unwrap().settings.get("recv") {
+        *recv
+    } else {
+        0
+    }
+}
+
+/// A header from the last response
+pub fn get_response_header(element: &ERow, name: &str) -> Option<String> {
+    let element = element.read().unwrap();
+    let Some(Type::HashMapSS(headers)) = element.settings.get("headers") else {
+        return None;
+    };
+
+    headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim().to_string())
+}
+
+/// The validator for `If-Range` from the last response, only a strong ETag can be used
+/// if is not will use Last-Modified
+pub fn get_validator(element: &ERow) -> Option<String> {
+    if let Some(etag) = get_response_header(element, "ETag") {
+        if !etag.starts_with("W/") {
+            return Some(etag);
+        }
+    }
+
+    get_response_header(element, "Last-Modified")
+}
+
 pub fn get_method(element: &ERow) -> Result<String, SessionError> {
     let error_i: u8;
 
@@ -270,7 +1361,7 @@ pub fn get_method(element: &ERow) -> Result<String, SessionError> {
         error_i = 0;
     }
 
-    return Err(error(
+    Err(error(
         element,
         match error_i {
             0 => "Error: has no method!",
@@ -278,7 +1369,7 @@ pub fn get_method(element: &ERow) -> Result<String, SessionError> {
             2 => "Error: method has noting selected!",
             _ => "IDK",
         },
-    ));
+    ))
 }
 
 pub fn get_headers(element: &ERow) -> HashMap<String, String> {
@@ -290,14 +1381,20 @@ pub fn get_headers(element: &ERow) -> HashMap<String, String> {
     headers
 }
 
-pub fn get_port(element: &ERow) -> Result<u16, SessionError> {
+pub fn get_port(element: &ERow, url: &Url) -> Result<u16, SessionError> {
     let error_i: u8;
 
     if let Some(data) = element.read().unwrap().element_data.get("port") {
-        if let Type::U16(port) = data {
-            return Ok(*port);
-        } else {
-            error_i = 1;
+        match data {
+            Type::U16(port) => return Ok(*port),
+            // if none will auto detect from url, this can change after a redirect
+            Type::None => {
+                if let Some(port) = url.port_or_known_default() {
+                    return Ok(port);
+                }
+                error_i = 2;
+            }
+            _ => error_i = 1,
         }
     } else {
         error_i = 0;
@@ -307,8 +1404,57 @@ pub fn get_port(element: &ERow) -> Result<u16, SessionError> {
         element,
         match error_i {
             0 => "Error: has no port",
-            1 => "Error: port should be u8",
+            1 => "Error: port should be u16",
+            2 => "Error: cannot detect port from url",
             _ => "IDK",
         },
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Read, net::TcpListener, thread, time::Duration};
+
+    use super::*;
+
+    #[test]
+    fn non_blocking() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut tcp, _) = listener.accept().unwrap();
+            let mut request = [0; 1024];
+            let len = tcp.read(&mut request).unwrap();
+            assert!(request[..len].starts_with(b"GET / HTTP/1.1\r\nHost: 127.0.0.1\r\n"));
+            // the head comes in two pieces, the first tick has only a part
+            tcp.write_all(b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\n")
+                .unwrap();
+            thread::sleep(Duration::from_millis(100));
+            tcp.write_all(b"Content-Length: 2\r\n\r\nok").unwrap();
+        });
+
+        let url = Url::parse(&format!("http://127.0.0.1:{}/", port)).unwrap();
+        let mut opening = open_connection(&url, port, &ConnectOptions::default()).unwrap();
+        let mut conn = loop {
+            if let Some(conn) = opening.poll().unwrap() {
+                break conn;
+            }
+            thread::sleep(Duration::from_millis(1));
+        };
+        send_request(&mut conn, &url, "GET", "/", "127.0.0.1", &HashMap::new()).unwrap();
+
+        let mut head = HeadReader::default();
+        let mut pending = 0;
+        let response = loop {
+            match read_response(&mut conn, &mut head, &ParseOptions::default()).unwrap() {
+                Some(response) => break response,
+                None => pending += 1,
+            }
+            thread::sleep(Duration::from_millis(1));
+        };
+        assert_eq!(response.status, 200);
+        assert_eq!(response.headers.get("Content-Length"), Some("2"));
+        assert!(pending > 0);
+        server.join().unwrap();
+    }
+}
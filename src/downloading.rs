@@ -2,9 +2,40 @@ use std::io::{Read, Write};
 
 use muzzman_lib::prelude::*;
 
-use crate::{connection::Connection, error};
+use crate::{
+    checksum,
+    chunked::ChunkedDecoder,
+    connection::Connection,
+    content_encoding::ContentDecoder,
+    error,
+    pool::{self, Reusable},
+    retry,
+    segments::{downloading_segments, Segments},
+    sse::{self, EventStream},
+    throttle::{self, Direction},
+    timeouts,
+    websocket::{downloading_websocket, WebSocket},
+};
 
 pub fn downloading(element: &ERow, storage: &mut Storage) -> Result<(), SessionError> {
+    // everything was downloaded, the data is hashed over more ticks
+    if checksum::is_rehashing(storage) {
+        return checksum::complete(element, storage);
+    }
+
+    if storage.get::<Segments>().is_some() {
+        return downloading_segments(element, storage);
+    }
+
+    if storage.get::<WebSocket>().is_some() {
+        return downloading_websocket(element, storage);
+    }
+
+    if sse::waiting_reconnect(element, storage) {
+        return Ok(());
+    }
+    let is_sse = storage.get::<EventStream>().is_some();
+
     let mut content_length: usize = 0;
     if let Some(Type::USize(data)) = element
         .read()
@@ -15,70 +46,193 @@ pub fn downloading(element: &ERow, storage: &mut Storage) -> Result<(), SessionE
         content_length = *data;
     }
 
-    if content_length > 0 {
-        let mut buffer_size = 0;
-        if let Some(Type::USize(len)) = element.read().unwrap().settings.get("buffer_size") {
-            buffer_size = *len;
-        }
+    // like a HEAD, 204, 304 or `Content-Length: 0`, there is nothing to read
+    if content_length == 0 {
+        release_connection(storage);
+        return complete(element, storage);
+    }
 
-        let mut buffer = vec![0; buffer_size];
-        let len;
+    let mut buffer_size = 0;
+    if let Some(Type::USize(len)) = element.read().unwrap().settings.get("buffer_size") {
+        buffer_size = *len;
+    }
 
-        {
-            let Some(conn) = storage.get_mut::<Connection>()else{
-                return Ok(());
-            };
+    let buffer_size = throttle::allowance(element, storage, Direction::Download, buffer_size);
+    if buffer_size == 0 {
+        return Ok(());
+    }
 
-            match conn.read(&mut buffer) {
-                Ok(size) => {
-                    len = size;
-                }
-                Err(err) => {
-                    match err.kind() {
-                        std::io::ErrorKind::WouldBlock => {}
-                        _ => return Err(error(element, "Error: Connection close unexpected!")),
+    let mut buffer = vec![0; buffer_size];
+    let len;
+
+    {
+        let Some(conn) = storage.get_mut::<Connection>() else {
+            return Ok(());
+        };
+
+        match conn.read(&mut buffer) {
+            Ok(size) => {
+                len = size;
+            }
+            Err(err) => {
+                match err.kind() {
+                    std::io::ErrorKind::WouldBlock => {
+                        // a event stream is reconnected, like when the server closes it
+                        if is_sse && timeouts::stalled(element, storage, 0) {
+                            log::warn!("Event stream stalled, reconnecting");
+                            return sse::disconnected(storage);
+                        }
+                        return timeouts::check_stall(element, storage, 0);
+                    }
+                    _ if is_sse => return sse::disconnected(storage),
+                    _ => {
+                        return retry::failed(
+                            element,
+                            storage,
+                            "Error: Connection close unexpected!",
+                            None,
+                        )
                     }
-                    return Ok(());
                 }
             }
         }
+    }
 
-        let recived;
+    throttle::consume(storage, Direction::Download, len);
+    timeouts::recived(storage, len);
 
-        'd: {
-            let mut element = element.write().unwrap();
-            if let Some(Type::USize(recived_b)) = element.settings.get_mut("recv") {
-                *recived_b += len;
-                recived = *recived_b;
-                break 'd;
-            }
+    // the Content-Length is for the body as is recived, before any decoding
+    let recived = match storage.get_mut::<Recived>() {
+        Some(recived) => {
+            recived.0 += len;
+            recived.0
+        }
+        None => {
+            storage.set(Recived(len));
+            len
+        }
+    };
 
-            recived = len;
-            element.settings.set("recv", Type::USize(len));
+    let mut chunked_done = None;
+    if let Some(decoder) = storage.get_mut::<ChunkedDecoder>() {
+        let mut decoded = Vec::with_capacity(len);
+        if let Err(err) = decoder.decode(&buffer[0..len], &mut decoded) {
+            return Err(error(
+                element,
+                format!("Error: invalid chunked body: {err}"),
+            ));
         }
+        chunked_done = Some(decoder.is_done());
+        buffer = decoded;
+    } else {
+        buffer.truncate(len);
+    }
+
+    if let Some(decoder) = storage.get_mut::<ContentDecoder>() {
+        buffer = match decoder.decode(buffer) {
+            Ok(decoded) => decoded,
+            Err(err) => return Err(error(element, format!("Error: {}", err))),
+        };
+    }
 
-        element
-            .write()
-            .unwrap()
-            .data
-            .write_all(&buffer[0..len])
-            .unwrap();
+    if is_sse {
+        sse::write_events(element, storage, &buffer);
+    } else {
+        element.write().unwrap().data.write_all(&buffer).unwrap();
+        checksum::update(storage, &buffer);
+    }
 
-        let progress = if content_length > 0 {
-            ((recived as f64) / (content_length as f64)) as f32
+    // what is in the data, from here will be resumed
+    {
+        let mut element = element.write().unwrap();
+        if let Some(Type::USize(writen)) = element.settings.get_mut("recv") {
+            *writen += buffer.len();
         } else {
-            50.0
-        };
+            element.settings.set("recv", Type::USize(buffer.len()));
+        }
+    }
+
+    let progress = if content_length > 0 {
+        ((recived as f64) / (content_length as f64)) as f32
+    } else {
+        50.0
+    };
 
-        element.write().unwrap().progress = progress;
-        if len == 0 {
-            element.set_status(8);
+    element.write().unwrap().progress = progress;
+
+    // a event stream has no end, when the server closes we reconnect
+    if is_sse {
+        if chunked_done == Some(true) || len == 0 {
+            return sse::disconnected(storage);
+        }
+        return Ok(());
+    }
+
+    if let Some(done) = chunked_done {
+        if done {
+            release_connection(storage);
+            return complete(element, storage);
+        } else if len == 0 {
+            return retry::failed(
+                element,
+                storage,
+                "Error: Connection closed before the last chunk!",
+                None,
+            );
         }
+        return Ok(());
+    }
+
+    if recived == content_length {
+        release_connection(storage);
+        return complete(element, storage);
+    }
 
-        if recived == content_length {
-            element.set_status(8);
+    if len == 0 {
+        // without a length the body is until the connection is closed
+        if content_length != usize::MAX && recived < content_length {
+            return retry::failed(
+                element,
+                storage,
+                "Error: Connection closed before all the body was recived!",
+                None,
+            );
         }
+        return complete(element, storage);
     }
 
     Ok(())
 }
+
+/// All the body was recived, the content decoder is finished and the checksum is verified
+fn complete(element: &ERow, storage: &mut Storage) -> Result<(), SessionError> {
+    if let Some(mut decoder) = storage.remove::<ContentDecoder>() {
+        let rest = match decoder.finish() {
+            Ok(rest) => rest,
+            Err(err) => return Err(error(element, format!("Error: {}", err))),
+        };
+        {
+            let mut element = element.write().unwrap();
+            element.data.write_all(&rest).unwrap();
+            if let Some(Type::USize(writen)) = element.settings.get_mut("recv") {
+                *writen += rest.len();
+            }
+        }
+        checksum::update(storage, &rest);
+    }
+
+    checksum::complete(element, storage)
+}
+
+/// The bytes of the body recived on the connection, with the start of the range
+/// `recv` is only what was writen in the data after decoding
+pub struct Recived(pub usize);
+
+/// The response was fully readed, if the connection can be reused is given to the pool
+fn release_connection(storage: &mut Storage) {
+    if let Some(reusable) = storage.remove::<Reusable>() {
+        if let Some(conn) = storage.remove::<Connection>() {
+            pool::put(reusable, conn);
+        }
+    }
+}
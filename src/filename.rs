@@ -0,0 +1,324 @@
+use muzzman_lib::prelude::*;
+use url::Url;
+
+use crate::response::Headers;
+
+/// If nothing is left after sanitizing
+const DEFAULT_NAME: &str = "download";
+/// Most filesystems are not accepting longer names
+const MAX_LEN: usize = 255;
+
+/// The last path segment without the query, `http://host/dir/` is `dir`
+pub fn from_url(url: &Url) -> String {
+    let name = url
+        .path_segments()
+        .and_then(|mut segments| segments.rfind(|segment| !segment.is_empty()))
+        .map(|segment| {
+            percent_encoding::percent_decode_str(segment)
+                .decode_utf8_lossy()
+                .to_string()
+        })
+        .and_then(|name| sanitize(&name))
+        .or_else(|| url.host_str().and_then(sanitize));
+
+    name.unwrap_or_else(|| DEFAULT_NAME.to_string())
+}
+
+/// RFC 6266, `filename*` (RFC 5987) is preferred to `filename`
+pub fn from_content_disposition(value: &str) -> Option<String> {
+    let mut filename = None;
+    let mut filename_ext = None;
+
+    for (name, param) in parse_params(value) {
+        match name.as_str() {
+            "filename*" => filename_ext = decode_ext_value(&param),
+            "filename" => filename = Some(param),
+            _ => {}
+        }
+    }
+
+    filename_ext
+        .and_then(|name| sanitize(&name))
+        .or_else(|| filename.and_then(|name| sanitize(&name)))
+}
+
+/// The params after the disposition type, the names are lowercase
+fn parse_params(value: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    let bytes = value.as_bytes();
+    // skip the disposition type
+    let mut i = value.find(';').map(|i| i + 1).unwrap_or(bytes.len());
+
+    while i < bytes.len() {
+        while i < bytes.len() && (bytes[i] == b';' || bytes[i].is_ascii_whitespace()) {
+            i += 1;
+        }
+
+        let start = i;
+        while i < bytes.len() && bytes[i] != b'=' && bytes[i] != b';' {
+            i += 1;
+        }
+        let name = value[start..i].trim().to_lowercase();
+        if i >= bytes.len() || bytes[i] == b';' {
+            continue;
+        }
+        i += 1;
+
+        while i < bytes.len() && (bytes[i] == b' ' || bytes[i] == b'\t') {
+            i += 1;
+        }
+
+        let param = if i < bytes.len() && bytes[i] == b'"' {
+            i += 1;
+            let mut quoted = Vec::new();
+            while i < bytes.len() && bytes[i] != b'"' {
+                if bytes[i] == b'\\' && i + 1 < bytes.len() {
+                    i += 1;
+                }
+                quoted.push(bytes[i]);
+                i += 1;
+            }
+            i += 1;
+            String::from_utf8_lossy(&quoted).to_string()
+        } else {
+            let start = i;
+            while i < bytes.len() && bytes[i] != b';' {
+                i += 1;
+            }
+            value[start..i].trim().to_string()
+        };
+
+        if !name.is_empty() {
+            params.push((name, param));
+        }
+    }
+
+    params
+}
+
+/// `UTF-8'en'%e2%82%ac%20rates.txt`, only UTF-8 and ISO-8859-1 are known
+fn decode_ext_value(value: &str) -> Option<String> {
+    let mut parts = value.splitn(3, '\'');
+    let charset = parts.next()?.trim();
+    let _language = parts.next()?;
+    let encoded = parts.next()?;
+
+    let bytes = percent_encoding::percent_decode_str(encoded).collect::<Vec<u8>>();
+    if charset.eq_ignore_ascii_case("UTF-8") {
+        String::from_utf8(bytes).ok()
+    } else if charset.eq_ignore_ascii_case("ISO-8859-1") {
+        Some(bytes.into_iter().map(char::from).collect())
+    } else {
+        None
+    }
+}
+
+/// Only the last component, without control and reserved characters
+/// and not a reserved name on windows, none if is empty
+pub fn sanitize(name: &str) -> Option<String> {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+
+    let name = name
+        .chars()
+        .map(|c| {
+            if c.is_control() || matches!(c, '<' | '>' | ':' | '"' | '|' | '?' | '*') {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect::<String>();
+
+    // windows is not accepting a name that ends with a dot or a space
+    let name = name.trim().trim_end_matches('.').trim_start_matches('.');
+    if name.is_empty() {
+        return None;
+    }
+
+    let stem = name.split('.').next().unwrap_or_default().to_uppercase();
+    let reserved = matches!(stem.as_str(), "CON" | "PRN" | "AUX" | "NUL")
+        || ((stem.starts_with("COM") || stem.starts_with("LPT"))
+            && stem.len() == 4
+            && stem.as_bytes()[3].is_ascii_digit());
+    let mut name = if reserved {
+        format!("_{}", name)
+    } else {
+        name.to_string()
+    };
+
+    if name.len() > MAX_LEN {
+        // the extension is kept
+        let extension = name
+            .rfind('.')
+            .map(|i| name[i..].to_string())
+            .filter(|extension| extension.len() < 16)
+            .unwrap_or_default();
+        let mut end = MAX_LEN - extension.len();
+        while !name.is_char_boundary(end) {
+            end -= 1;
+        }
+        name = format!("{}{}", &name[..end], extension);
+    }
+
+    Some(name)
+}
+
+/// For a name without extension
+pub fn extension_for(content_type: &str) -> Option<&'static str> {
+    let mime = content_type.split(';').next()?.trim().to_lowercase();
+    Some(match mime.as_str() {
+        "text/html" => "html",
+        "text/plain" => "txt",
+        "text/css" => "css",
+        "text/csv" => "csv",
+        "text/xml" | "application/xml" => "xml",
+        "text/javascript" | "application/javascript" => "js",
+        "application/json" => "json",
+        "application/pdf" => "pdf",
+        "application/zip" => "zip",
+        "application/gzip" | "application/x-gzip" => "gz",
+        "application/x-tar" => "tar",
+        "application/x-xz" => "xz",
+        "application/zstd" => "zst",
+        "application/x-7z-compressed" => "7z",
+        "application/vnd.rar" | "application/x-rar-compressed" => "rar",
+        "application/x-bittorrent" => "torrent",
+        "application/wasm" => "wasm",
+        "application/vnd.debian.binary-package" => "deb",
+        "application/x-rpm" => "rpm",
+        "application/x-msdownload" => "exe",
+        "application/x-iso9660-image" => "iso",
+        "image/png" => "png",
+        "image/jpeg" => "jpg",
+        "image/gif" => "gif",
+        "image/webp" => "webp",
+        "image/svg+xml" => "svg",
+        "image/avif" => "avif",
+        "image/x-icon" | "image/vnd.microsoft.icon" => "ico",
+        "audio/mpeg" => "mp3",
+        "audio/ogg" => "ogg",
+        "audio/flac" => "flac",
+        "audio/wav" | "audio/x-wav" => "wav",
+        "video/mp4" => "mp4",
+        "video/webm" => "webm",
+        "video/x-matroska" => "mkv",
+        "font/woff2" => "woff2",
+        _ => return None,
+    })
+}
+
+/// After the response headers, the name is from `Content-Disposition` or from the last url
+/// if has no extension one is guessed from `Content-Type`
+pub fn rename(element: &ERow, url: &Url, headers: &Headers) {
+    let mut name = headers
+        .get("Content-Disposition")
+        .and_then(from_content_disposition)
+        .unwrap_or_else(|| from_url(url));
+
+    let has_extension = name.rfind('.').map(|i| i > 0).unwrap_or(false);
+    if !has_extension {
+        if let Some(extension) = headers.get("Content-Type").and_then(extension_for) {
+            name = format!("{}.{}", name, extension);
+        }
+    }
+
+    let mut element = element.write().unwrap();
+    if element.name != name {
+        log::info!("Renamed to: {}", name);
+        element.name = name;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(url: &str) -> Url {
+        Url::parse(url).unwrap()
+    }
+
+    #[test]
+    fn names_from_url() {
+        assert_eq!(
+            from_url(&url("http://host/dir/file.tar.gz?x=1")),
+            "file.tar.gz"
+        );
+        assert_eq!(from_url(&url("http://host/dir/")), "dir");
+        assert_eq!(from_url(&url("http://host/a%20b%2Fc.txt")), "c.txt");
+        assert_eq!(from_url(&url("http://example.com/")), "example.com");
+        assert_eq!(from_url(&url("http://[::1]/")), "[__1]");
+    }
+
+    #[test]
+    fn content_disposition() {
+        assert_eq!(
+            from_content_disposition("attachment; filename=\"plain.txt\"").as_deref(),
+            Some("plain.txt")
+        );
+        assert_eq!(
+            from_content_disposition("attachment; filename=a.txt; size=1").as_deref(),
+            Some("a.txt")
+        );
+        // filename* is used even if is before
+        assert_eq!(
+            from_content_disposition(
+                "attachment; filename*=UTF-8''%e2%82%ac%20rates.txt; filename=\"rates.txt\""
+            )
+            .as_deref(),
+            Some("€ rates.txt")
+        );
+        assert_eq!(
+            from_content_disposition("inline; FILENAME*=iso-8859-1'en'%A3%20rates").as_deref(),
+            Some("£ rates")
+        );
+        assert_eq!(
+            from_content_disposition("attachment; filename=\"with \\\"quote\\\".txt\"").as_deref(),
+            Some("with _quote_.txt")
+        );
+        // a unknown charset is ignored and filename is used
+        assert_eq!(
+            from_content_disposition("attachment; filename*=KOI8-R''x; filename=y").as_deref(),
+            Some("y")
+        );
+        assert_eq!(from_content_disposition("attachment"), None);
+        assert_eq!(from_content_disposition("attachment; filename=\"\""), None);
+    }
+
+    #[test]
+    fn sanitized() {
+        assert_eq!(sanitize("../../etc/passwd").as_deref(), Some("passwd"));
+        assert_eq!(
+            sanitize("C:\\Windows\\evil.exe").as_deref(),
+            Some("evil.exe")
+        );
+        assert_eq!(
+            sanitize("a<b>c:d|e?f*g\u{7}").as_deref(),
+            Some("a_b_c_d_e_f_g_")
+        );
+        assert_eq!(sanitize(" .hidden. ").as_deref(), Some("hidden"));
+        assert_eq!(sanitize("con.txt").as_deref(), Some("_con.txt"));
+        assert_eq!(sanitize("COM1").as_deref(), Some("_COM1"));
+        assert_eq!(sanitize("COM10").as_deref(), Some("COM10"));
+        assert_eq!(sanitize("..."), None);
+        assert_eq!(sanitize("dir/"), None);
+    }
+
+    #[test]
+    fn long_names() {
+        let name = sanitize(&format!("{}.tar.gz", "a".repeat(300))).unwrap();
+        assert_eq!(name.len(), MAX_LEN);
+        assert!(name.ends_with("a.gz"));
+
+        // not cut in the middle of a character
+        let name = sanitize(&"é".repeat(200)).unwrap();
+        assert!(name.len() <= MAX_LEN);
+        assert!(name.chars().all(|c| c == 'é'));
+    }
+
+    #[test]
+    fn extensions() {
+        assert_eq!(extension_for("text/html; charset=utf-8"), Some("html"));
+        assert_eq!(extension_for("Application/JSON"), Some("json"));
+        assert_eq!(extension_for("application/octet-stream"), None);
+    }
+}
@@ -0,0 +1,1012 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, Read, Write},
+    net::TcpStream,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use rustls::{Certificate, ClientConnection};
+use url::Url;
+
+use crate::{
+    connection::send_tls,
+    pool::PoolKey,
+    request::RequestTarget,
+    response::{Headers, ResponseHead},
+};
+
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// The stream window that we advertise, the default of 65535 is too small for downloads
+const WINDOW: u32 = 1024 * 1024;
+const DEFAULT_WINDOW: i64 = 65535;
+const DEFAULT_MAX_FRAME_SIZE: usize = 16384;
+
+// Frame types
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+// Flags
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY: u8 = 0x20;
+
+// Settings
+const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+
+// Error codes
+const PROTOCOL_ERROR: u32 = 0x1;
+const CANCEL: u32 = 0x8;
+
+/// Headers that are only for HTTP/1.1 and are not allowed in HTTP/2
+const CONNECTION_HEADERS: [&str; 6] = [
+    "host",
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+#[derive(Default)]
+struct Stream {
+    headers: Option<Vec<(String, String)>>,
+    data: VecDeque<u8>,
+    ended: bool,
+    reset: Option<u32>,
+    send_window: i64,
+    /// Recived and readed but not given back with WINDOW_UPDATE
+    consumed: u32,
+    /// How much of the body is left to send, after END_STREAM is sent
+    body_left: usize,
+}
+
+/// A HTTP/2 connection over tls, every element has his own stream
+pub struct H2Connection {
+    tls: ClientConnection,
+    tcp: TcpStream,
+    encoder: hpack::Encoder<'static>,
+    decoder: hpack::Decoder<'static>,
+    streams: HashMap<u32, Stream>,
+    next_stream_id: u32,
+    send_window: i64,
+    initial_send_window: i64,
+    max_frame_size: usize,
+    max_concurrent_streams: usize,
+    /// A header block that waits for CONTINUATION: stream, flags, block
+    header_block: Option<(u32, u8, Vec<u8>)>,
+    buffer: Vec<u8>,
+    closed: Option<String>,
+}
+
+impl H2Connection {
+    /// `tls` should have finished the handshake with `h2` as alpn protocol
+    /// the socket is non-blocking, no element waits for a other one
+    /// the tls should have no buffer limit, a frame is never writen in part
+    pub fn new(tls: ClientConnection, tcp: TcpStream) -> io::Result<Self> {
+        tcp.set_nonblocking(true)?;
+        let mut conn = Self {
+            tls,
+            tcp,
+            encoder: hpack::Encoder::new(),
+            decoder: hpack::Decoder::new(),
+            streams: HashMap::new(),
+            next_stream_id: 1,
+            send_window: DEFAULT_WINDOW,
+            initial_send_window: DEFAULT_WINDOW,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            max_concurrent_streams: 100,
+            header_block: None,
+            buffer: Vec::new(),
+            closed: None,
+        };
+
+        conn.tls.writer().write_all(PREFACE)?;
+
+        let mut settings = Vec::new();
+        settings.extend_from_slice(&SETTINGS_ENABLE_PUSH.to_be_bytes());
+        settings.extend_from_slice(&0u32.to_be_bytes());
+        settings.extend_from_slice(&SETTINGS_INITIAL_WINDOW_SIZE.to_be_bytes());
+        settings.extend_from_slice(&WINDOW.to_be_bytes());
+        conn.write_frame(SETTINGS, 0, 0, &settings)?;
+
+        // the connection window can only be changed with WINDOW_UPDATE
+        conn.write_frame(
+            WINDOW_UPDATE,
+            0,
+            0,
+            &(WINDOW - DEFAULT_WINDOW as u32).to_be_bytes(),
+        )?;
+
+        Ok(conn)
+    }
+
+    /// If a new stream can be opened on this connection
+    pub fn is_usable(&self) -> bool {
+        self.closed.is_none() && self.streams.len() < self.max_concurrent_streams
+    }
+
+    /// Handles what was recived while no stream was reading, like a GOAWAY or the close
+    /// so a connection that is not usable anymore is not given to a new stream
+    pub fn check(&mut self) -> bool {
+        while self.pump().is_ok() {}
+        self.is_usable()
+    }
+
+    fn write_frame(&mut self, kind: u8, flags: u8, stream: u32, payload: &[u8]) -> io::Result<()> {
+        // the tls has no buffer limit, what the socket does not take is sent later
+        let frame = encode_frame(kind, flags, stream, payload);
+        let res = self
+            .tls
+            .writer()
+            .write_all(&frame)
+            .and_then(|_| send_tls(&mut self.tls, &mut self.tcp));
+
+        // a frame that was writen only in part cannot be continued
+        if let Err(err) = &res {
+            self.close(err.to_string());
+        }
+        res
+    }
+
+    /// Reads more plain text from tls in the buffer, `WouldBlock` if nothing was recived
+    fn read_more(&mut self) -> io::Result<()> {
+        let mut buffer = [0; 16384];
+        loop {
+            match self.tls.reader().read(&mut buffer) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "HTTP/2 connection closed",
+                    ))
+                }
+                Ok(len) => {
+                    self.buffer.extend_from_slice(&buffer[0..len]);
+                    return Ok(());
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    if self.tls.read_tls(&mut self.tcp)? == 0 {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "HTTP/2 connection closed",
+                        ));
+                    }
+                    self.tls
+                        .process_new_packets()
+                        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                    // the server can want something like a key update
+                    send_tls(&mut self.tls, &mut self.tcp)?;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Reads and handles one frame, `WouldBlock` if a whole frame was not recived yet
+    /// the part that was recived stays in the buffer
+    fn pump(&mut self) -> io::Result<()> {
+        if let Some(closed) = &self.closed {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                closed.clone(),
+            ));
+        }
+
+        // what was not sent before is sent first
+        let res = send_tls(&mut self.tls, &mut self.tcp)
+            .and_then(|_| self.read_frame())
+            .and_then(|(kind, flags, stream, payload)| {
+                self.handle_frame(kind, flags, stream, payload)
+            });
+
+        // only a error closes the connection, not the waiting
+        if let Err(err) = &res {
+            if !matches!(
+                err.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::Interrupted
+            ) {
+                self.close(err.to_string());
+            }
+        }
+        res
+    }
+
+    fn read_frame(&mut self) -> io::Result<(u8, u8, u32, Vec<u8>)> {
+        loop {
+            if self.buffer.len() >= 9 {
+                let len = u32::from_be_bytes([0, self.buffer[0], self.buffer[1], self.buffer[2]])
+                    as usize;
+                if self.buffer.len() >= 9 + len {
+                    let kind = self.buffer[3];
+                    let flags = self.buffer[4];
+                    let stream = u32::from_be_bytes([
+                        self.buffer[5],
+                        self.buffer[6],
+                        self.buffer[7],
+                        self.buffer[8],
+                    ]) & 0x7fff_ffff;
+                    let payload = self.buffer[9..9 + len].to_vec();
+                    self.buffer.drain(0..9 + len);
+                    return Ok((kind, flags, stream, payload));
+                }
+            }
+            self.read_more()?;
+        }
+    }
+
+    fn handle_frame(
+        &mut self,
+        kind: u8,
+        flags: u8,
+        stream: u32,
+        payload: Vec<u8>,
+    ) -> io::Result<()> {
+        if let Some((block_stream, _, _)) = &self.header_block {
+            if kind != CONTINUATION || *block_stream != stream {
+                return Err(protocol_error("expected CONTINUATION"));
+            }
+        }
+
+        match kind {
+            DATA => {
+                let data = strip_padding(flags, &payload)?;
+                // the connection window is given back directly, the stream window when is readed
+                if !payload.is_empty() {
+                    self.write_frame(WINDOW_UPDATE, 0, 0, &(payload.len() as u32).to_be_bytes())?;
+                }
+                if let Some(s) = self.streams.get_mut(&stream) {
+                    s.data.extend(data);
+                    s.consumed += (payload.len() - data.len()) as u32;
+                    if flags & END_STREAM != 0 {
+                        s.ended = true;
+                    }
+                }
+            }
+            HEADERS => {
+                let mut block = strip_padding(flags, &payload)?;
+                if flags & PRIORITY != 0 {
+                    if block.len() < 5 {
+                        return Err(protocol_error("invalid HEADERS frame"));
+                    }
+                    block = &block[5..];
+                }
+                let block = block.to_vec();
+                if flags & END_HEADERS != 0 {
+                    self.handle_header_block(stream, flags, &block)?;
+                } else {
+                    self.header_block = Some((stream, flags, block));
+                }
+            }
+            CONTINUATION => {
+                let Some((block_stream, block_flags, mut block)) = self.header_block.take() else {
+                    return Err(protocol_error("unexpected CONTINUATION"));
+                };
+                block.extend_from_slice(&payload);
+                if flags & END_HEADERS != 0 {
+                    self.handle_header_block(block_stream, block_flags, &block)?;
+                } else {
+                    self.header_block = Some((block_stream, block_flags, block));
+                }
+            }
+            RST_STREAM => {
+                if payload.len() != 4 {
+                    return Err(protocol_error("invalid RST_STREAM frame"));
+                }
+                if let Some(s) = self.streams.get_mut(&stream) {
+                    s.reset = Some(u32::from_be_bytes([
+                        payload[0], payload[1], payload[2], payload[3],
+                    ]));
+                }
+            }
+            SETTINGS if flags & ACK == 0 => {
+                for setting in payload.chunks_exact(6) {
+                    let id = u16::from_be_bytes([setting[0], setting[1]]);
+                    let value =
+                        u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+                    match id {
+                        SETTINGS_MAX_CONCURRENT_STREAMS => {
+                            self.max_concurrent_streams = value as usize
+                        }
+                        SETTINGS_INITIAL_WINDOW_SIZE => {
+                            let delta = value as i64 - self.initial_send_window;
+                            self.initial_send_window = value as i64;
+                            for s in self.streams.values_mut() {
+                                s.send_window += delta;
+                            }
+                        }
+                        SETTINGS_MAX_FRAME_SIZE => self.max_frame_size = value as usize,
+                        _ => {}
+                    }
+                }
+                self.write_frame(SETTINGS, ACK, 0, &[])?;
+            }
+            PUSH_PROMISE => {
+                // push is disabled in our settings
+                return Err(protocol_error("unexpected PUSH_PROMISE"));
+            }
+            PING if flags & ACK == 0 => {
+                self.write_frame(PING, ACK, 0, &payload)?;
+            }
+            GOAWAY => {
+                if payload.len() < 8 {
+                    return Err(protocol_error("invalid GOAWAY frame"));
+                }
+                let last_stream =
+                    u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]])
+                        & 0x7fff_ffff;
+                let code = u32::from_be_bytes([payload[4], payload[5], payload[6], payload[7]]);
+                log::info!("HTTP/2 GOAWAY: last stream {}, code {}", last_stream, code);
+                // no more new streams, the streams before last_stream can finish
+                self.max_concurrent_streams = 0;
+                for (id, s) in self.streams.iter_mut() {
+                    if *id > last_stream {
+                        s.reset = Some(code);
+                    }
+                }
+            }
+            WINDOW_UPDATE => {
+                if payload.len() != 4 {
+                    return Err(protocol_error("invalid WINDOW_UPDATE frame"));
+                }
+                let increment =
+                    (u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]])
+                        & 0x7fff_ffff) as i64;
+                if stream == 0 {
+                    self.send_window += increment;
+                } else if let Some(s) = self.streams.get_mut(&stream) {
+                    s.send_window += increment;
+                }
+            }
+            // the ACKs, PRIORITY and unknown frames are ignored
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn handle_header_block(&mut self, stream: u32, flags: u8, block: &[u8]) -> io::Result<()> {
+        // should be decoded even if the stream is not ours, the hpack table is for the connection
+        let headers = self
+            .decoder
+            .decode(block)
+            .map_err(|err| protocol_error(&format!("hpack: {:?}", err)))?;
+
+        let headers = headers
+            .into_iter()
+            .map(|(name, value)| {
+                (
+                    String::from_utf8_lossy(&name).into_owned(),
+                    String::from_utf8_lossy(&value).into_owned(),
+                )
+            })
+            .collect::<Vec<(String, String)>>();
+
+        let Some(s) = self.streams.get_mut(&stream) else {
+            return Ok(());
+        };
+
+        let informational = headers
+            .iter()
+            .any(|(name, value)| name == ":status" && value.starts_with('1'));
+
+        // 1xx responses are ignored and the second header block are the trailers
+        if s.headers.is_none() && !informational {
+            s.headers = Some(headers);
+        }
+
+        if flags & END_STREAM != 0 {
+            s.ended = true;
+        }
+        Ok(())
+    }
+
+    fn close(&mut self, reason: String) {
+        log::error!("HTTP/2 connection closed: {}", reason);
+        for s in self.streams.values_mut() {
+            s.reset.get_or_insert(PROTOCOL_ERROR);
+        }
+        self.closed = Some(reason);
+    }
+
+    /// Opens a new stream with `headers`, if `body_length` is 0 the stream is ended
+    pub fn open_stream(
+        &mut self,
+        headers: Vec<(String, String)>,
+        body_length: usize,
+    ) -> io::Result<u32> {
+        if !self.is_usable() {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                "HTTP/2 connection cannot open more streams",
+            ));
+        }
+
+        let id = self.next_stream_id;
+        self.next_stream_id += 2;
+
+        let block = self.encoder.encode(
+            headers
+                .iter()
+                .map(|(name, value)| (name.as_bytes(), value.as_bytes())),
+        );
+
+        self.streams.insert(
+            id,
+            Stream {
+                send_window: self.initial_send_window,
+                body_left: body_length,
+                ..Default::default()
+            },
+        );
+
+        let end_stream = if body_length == 0 { END_STREAM } else { 0 };
+        let mut chunks = block.chunks(self.max_frame_size).peekable();
+        let mut first = true;
+        // a empty header block is not possible, there are always the pseudo headers
+        while let Some(chunk) = chunks.next() {
+            let last = chunks.peek().is_none();
+            let end_headers = if last { END_HEADERS } else { 0 };
+            if first {
+                self.write_frame(HEADERS, end_stream | end_headers, id, chunk)?;
+                first = false;
+            } else {
+                self.write_frame(CONTINUATION, end_headers, id, chunk)?;
+            }
+        }
+
+        Ok(id)
+    }
+
+    /// The response headers of `stream`, `WouldBlock` while they are not recived
+    pub fn response(&mut self, stream: u32) -> io::Result<Vec<(String, String)>> {
+        loop {
+            let Some(s) = self.streams.get(&stream) else {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "HTTP/2 stream not found",
+                ));
+            };
+            if let Some(headers) = &s.headers {
+                return Ok(headers.clone());
+            }
+            if let Some(code) = s.reset {
+                return Err(reset_error(code));
+            }
+            self.pump()?;
+        }
+    }
+
+    /// Like a non-blocking socket, `WouldBlock` if there is no data for `stream`
+    pub fn recv_data(&mut self, stream: u32, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let Some(s) = self.streams.get_mut(&stream) else {
+                return Ok(0);
+            };
+
+            if !s.data.is_empty() {
+                let len = buf.len().min(s.data.len());
+                for (b, d) in buf.iter_mut().zip(s.data.drain(0..len)) {
+                    *b = d;
+                }
+                s.consumed += len as u32;
+
+                if s.consumed >= WINDOW / 2 && !s.ended {
+                    let consumed = s.consumed;
+                    s.consumed = 0;
+                    self.write_frame(WINDOW_UPDATE, 0, stream, &consumed.to_be_bytes())?;
+                }
+                return Ok(len);
+            }
+
+            if s.ended {
+                return Ok(0);
+            }
+            if let Some(code) = s.reset {
+                return Err(reset_error(code));
+            }
+            self.pump()?;
+        }
+    }
+
+    /// `WouldBlock` while the flow control window is full
+    pub fn send_data(&mut self, stream: u32, buf: &[u8]) -> io::Result<usize> {
+        loop {
+            let Some(s) = self.streams.get(&stream) else {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "HTTP/2 stream not found",
+                ));
+            };
+            if let Some(code) = s.reset {
+                return Err(reset_error(code));
+            }
+            if s.body_left == 0 || buf.is_empty() {
+                return Ok(0);
+            }
+
+            // the socket is full, is waited like for the window
+            if self.tls.wants_write() {
+                self.pump()?;
+                continue;
+            }
+
+            let len = buf
+                .len()
+                .min(s.body_left)
+                .min(self.max_frame_size)
+                .min(s.send_window.max(0) as usize)
+                .min(self.send_window.max(0) as usize);
+
+            if len == 0 {
+                // waiting for WINDOW_UPDATE
+                self.pump()?;
+                continue;
+            }
+
+            let body_left = s.body_left - len;
+            let flags = if body_left == 0 { END_STREAM } else { 0 };
+            self.write_frame(DATA, flags, stream, &buf[0..len])?;
+
+            let s = self.streams.get_mut(&stream).unwrap();
+            s.body_left = body_left;
+            s.send_window -= len as i64;
+            self.send_window -= len as i64;
+            return Ok(len);
+        }
+    }
+
+    /// The stream is not needed anymore, if is not ended is canceled
+    pub fn close_stream(&mut self, stream: u32) {
+        if let Some(s) = self.streams.remove(&stream) {
+            if !s.ended && s.reset.is_none() && self.closed.is_none() {
+                let _ = self.write_frame(RST_STREAM, 0, stream, &CANCEL.to_be_bytes());
+            }
+        }
+    }
+}
+
+fn encode_frame(kind: u8, flags: u8, stream: u32, payload: &[u8]) -> Vec<u8> {
+    let len = payload.len() as u32;
+    let mut frame = Vec::with_capacity(9 + payload.len());
+    frame.extend_from_slice(&len.to_be_bytes()[1..]);
+    frame.push(kind);
+    frame.push(flags);
+    frame.extend_from_slice(&(stream & 0x7fff_ffff).to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn strip_padding(flags: u8, payload: &[u8]) -> io::Result<&[u8]> {
+    if flags & PADDED == 0 {
+        return Ok(payload);
+    }
+    let Some(pad) = payload.first() else {
+        return Err(protocol_error("invalid padding"));
+    };
+    let pad = *pad as usize;
+    if pad + 1 > payload.len() {
+        return Err(protocol_error("invalid padding"));
+    }
+    Ok(&payload[1..payload.len() - pad])
+}
+
+fn protocol_error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("HTTP/2: {}", msg))
+}
+
+fn reset_error(code: u32) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::ConnectionReset,
+        format!("HTTP/2 stream reset with code: {}", code),
+    )
+}
+
+/// A request on a shared `H2Connection`
+pub struct H2Stream {
+    conn: Arc<Mutex<H2Connection>>,
+    /// 0 until the request is sent
+    id: u32,
+}
+
+impl H2Stream {
+    pub fn new(conn: Arc<Mutex<H2Connection>>) -> Self {
+        Self { conn, id: 0 }
+    }
+
+    /// If something was writen on the connection but is not sent yet
+    pub fn is_pending(&self) -> bool {
+        self.conn.lock().unwrap().tls.wants_write()
+    }
+
+    /// If the connection was not closed and got no GOAWAY
+    pub fn is_usable(&self) -> bool {
+        self.conn.lock().unwrap().is_usable()
+    }
+
+    pub fn peer_certificates(&self) -> Option<Vec<Certificate>> {
+        let conn = self.conn.lock().unwrap();
+        conn.tls.peer_certificates().map(|certs| certs.to_vec())
+    }
+
+    pub fn send_request(
+        &mut self,
+        url: &Url,
+        method: &str,
+        target: &str,
+        host: &str,
+        headers: &HashMap<String, String>,
+    ) -> io::Result<()> {
+        let mut list = vec![(":method".to_string(), method.to_string())];
+        // CONNECT has only :authority, and :path is never absolute in HTTP/2
+        if method != "CONNECT" {
+            let path = if target.starts_with('/') || target == "*" {
+                target.to_string()
+            } else {
+                RequestTarget::Origin.build(url, 0)
+            };
+            list.push((":scheme".to_string(), url.scheme().to_string()));
+            list.push((":path".to_string(), path));
+        }
+        list.push((":authority".to_string(), host.to_string()));
+
+        let mut body_length = 0;
+        for (name, value) in headers {
+            let name = name.trim().to_lowercase();
+            if CONNECTION_HEADERS.contains(&name.as_str()) {
+                continue;
+            }
+            if name == "content-length" {
+                body_length = value.trim().parse().unwrap_or(0);
+            }
+            list.push((name, value.trim().to_string()));
+        }
+
+        self.id = self.conn.lock().unwrap().open_stream(list, body_length)?;
+        Ok(())
+    }
+
+    /// The status and the headers, the names are lowercase
+    pub fn read_response(&mut self) -> io::Result<ResponseHead> {
+        let list = self.conn.lock().unwrap().response(self.id)?;
+
+        let mut status = 0;
+        let mut headers = Vec::new();
+        for (name, value) in list {
+            if name == ":status" {
+                status = value.parse().unwrap_or(0);
+                continue;
+            }
+            if name.starts_with(':') {
+                continue;
+            }
+            headers.push((name, value));
+        }
+
+        Ok(ResponseHead {
+            version: "HTTP/2".to_string(),
+            status,
+            reason: String::new(),
+            headers: Headers::new(headers),
+        })
+    }
+}
+
+impl Read for H2Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.conn.lock().unwrap().recv_data(self.id, buf)
+    }
+}
+
+impl Write for H2Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.conn.lock().unwrap().send_data(self.id, buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for H2Stream {
+    fn drop(&mut self) {
+        if self.id != 0 {
+            if let Ok(mut conn) = self.conn.lock() {
+                conn.close_stream(self.id);
+            }
+        }
+    }
+}
+
+fn connections() -> &'static Mutex<HashMap<PoolKey, Arc<Mutex<H2Connection>>>> {
+    static CONNECTIONS: OnceLock<Mutex<HashMap<PoolKey, Arc<Mutex<H2Connection>>>>> =
+        OnceLock::new();
+    CONNECTIONS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// A HTTP/2 connection for the same origin that can have one more stream
+pub fn get(key: &PoolKey) -> Option<Arc<Mutex<H2Connection>>> {
+    let mut connections = connections().lock().unwrap();
+    let conn = connections.get(key)?.clone();
+
+    // if is locked is used now by a other element, so is alive
+    let usable = match conn.try_lock() {
+        Ok(mut conn) => conn.check(),
+        Err(_) => true,
+    };
+
+    if usable {
+        Some(conn)
+    } else {
+        connections.remove(key);
+        None
+    }
+}
+
+pub fn register(key: PoolKey, conn: H2Connection) -> Arc<Mutex<H2Connection>> {
+    let conn = Arc::new(Mutex::new(conn));
+    connections().lock().unwrap().insert(key, conn.clone());
+    conn
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::TcpListener,
+        sync::mpsc,
+        thread::{self, JoinHandle},
+        time::{Duration, Instant},
+    };
+
+    use rustls::{ServerConnection, StreamOwned};
+
+    use super::*;
+    use crate::{creating_connection::ConnectOptions, tls};
+
+    type Server = StreamOwned<ServerConnection, TcpStream>;
+
+    /// A tls server that after the preface does what `script` says, and a connection to it
+    fn connect(
+        script: impl FnOnce(&mut Server) + Send + 'static,
+    ) -> (H2Connection, JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (tcp, _) = listener.accept().unwrap();
+            let tls =
+                ServerConnection::new(tls::tests::server_config(vec![b"h2".to_vec()])).unwrap();
+            let mut server = StreamOwned::new(tls, tcp);
+            let mut preface = [0; 24];
+            server.read_exact(&mut preface).unwrap();
+            assert_eq!(preface, PREFACE);
+            script(&mut server);
+        });
+
+        let config = tls::TlsOptions {
+            insecure_skip_verify: true,
+            ..Default::default()
+        }
+        .client_config(vec![b"h2".to_vec()])
+        .unwrap();
+        let mut tls =
+            ClientConnection::new(Arc::new(config), "localhost".try_into().unwrap()).unwrap();
+        let mut tcp = TcpStream::connect(addr).unwrap();
+        while tls.is_handshaking() {
+            tls.complete_io(&mut tcp).unwrap();
+        }
+        assert_eq!(tls.alpn_protocol(), Some(&b"h2"[..]));
+        tls.set_buffer_limit(None);
+        (H2Connection::new(tls, tcp).unwrap(), server)
+    }
+
+    fn send(server: &mut Server, kind: u8, flags: u8, stream: u32, payload: &[u8]) {
+        server
+            .write_all(&encode_frame(kind, flags, stream, payload))
+            .unwrap();
+        server.flush().unwrap();
+    }
+
+    /// The next frame of `kind` from the client: flags, stream and payload
+    fn expect(server: &mut Server, kind: u8) -> (u8, u32, Vec<u8>) {
+        loop {
+            let mut head = [0; 9];
+            server.read_exact(&mut head).unwrap();
+            let len = u32::from_be_bytes([0, head[0], head[1], head[2]]) as usize;
+            let mut payload = vec![0; len];
+            server.read_exact(&mut payload).unwrap();
+            if head[3] == kind {
+                let stream = u32::from_be_bytes([head[5], head[6], head[7], head[8]]);
+                return (head[4], stream, payload);
+            }
+        }
+    }
+
+    /// Like a element that tries again every tick
+    fn wait<T>(mut f: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+        let start = Instant::now();
+        loop {
+            match f() {
+                Err(err)
+                    if err.kind() == io::ErrorKind::WouldBlock
+                        && start.elapsed() < Duration::from_secs(5) =>
+                {
+                    thread::sleep(Duration::from_millis(1))
+                }
+                res => return res,
+            }
+        }
+    }
+
+    fn request(method: &str) -> Vec<(String, String)> {
+        [
+            (":method", method),
+            (":scheme", "https"),
+            (":path", "/"),
+            (":authority", "localhost"),
+        ]
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect()
+    }
+
+    #[test]
+    fn response() {
+        let (mut conn, server) = connect(|server| {
+            let (flags, stream, _) = expect(server, HEADERS);
+            assert_eq!(flags, END_STREAM | END_HEADERS);
+            // `:status: 200` is in the static table, `x-test: a` is a literal
+            send(server, HEADERS, 0, stream, &[0x88]);
+            send(
+                server,
+                CONTINUATION,
+                END_HEADERS,
+                stream,
+                b"\x00\x06x-test\x01a",
+            );
+            send(
+                server,
+                DATA,
+                PADDED | END_STREAM,
+                stream,
+                b"\x03hello\0\0\0",
+            );
+            // the padding is also given back
+            let (_, window_stream, increment) = expect(server, WINDOW_UPDATE);
+            assert_eq!(window_stream, 0);
+            assert_eq!(increment, 9u32.to_be_bytes());
+        });
+
+        let id = conn.open_stream(request("GET"), 0).unwrap();
+        let headers = wait(|| conn.response(id)).unwrap();
+        assert!(headers.contains(&(":status".to_string(), "200".to_string())));
+        assert!(headers.contains(&("x-test".to_string(), "a".to_string())));
+
+        let mut buffer = [0; 16];
+        assert_eq!(wait(|| conn.recv_data(id, &mut buffer)).unwrap(), 5);
+        assert_eq!(&buffer[0..5], b"hello");
+        assert_eq!(wait(|| conn.recv_data(id, &mut buffer)).unwrap(), 0);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn missing_continuation() {
+        let (mut conn, server) = connect(|server| {
+            let (_, stream, _) = expect(server, HEADERS);
+            send(server, HEADERS, 0, stream, &[0x88]);
+            send(server, DATA, END_STREAM, stream, b"hello");
+        });
+
+        let id = conn.open_stream(request("GET"), 0).unwrap();
+        let err = wait(|| conn.response(id)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(!conn.is_usable());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn flow_control() {
+        let (mut conn, server) = connect(|server| {
+            let mut settings = SETTINGS_INITIAL_WINDOW_SIZE.to_be_bytes().to_vec();
+            settings.extend_from_slice(&10u32.to_be_bytes());
+            send(server, SETTINGS, 0, 0, &settings);
+
+            let (flags, stream, _) = expect(server, HEADERS);
+            assert_eq!(flags, END_HEADERS);
+            let (flags, _, data) = expect(server, DATA);
+            assert_eq!((flags, data.len()), (0, 10));
+            send(server, WINDOW_UPDATE, 0, stream, &5u32.to_be_bytes());
+            let (flags, _, data) = expect(server, DATA);
+            assert_eq!((flags, data.len()), (0, 5));
+        });
+
+        wait(|| conn.pump()).unwrap();
+        assert_eq!(conn.initial_send_window, 10);
+
+        let id = conn.open_stream(request("POST"), 100).unwrap();
+        assert_eq!(conn.send_data(id, &[1; 100]).unwrap(), 10);
+        // the window is full until the WINDOW_UPDATE
+        assert_eq!(wait(|| conn.send_data(id, &[1; 90])).unwrap(), 5);
+        assert_eq!(conn.streams[&id].send_window, 0);
+        assert_eq!(conn.streams[&id].body_left, 85);
+        assert_eq!(conn.send_window, DEFAULT_WINDOW - 15);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn goaway() {
+        let (mut conn, server) = connect(|server| {
+            expect(server, HEADERS);
+            expect(server, HEADERS);
+            let mut payload = 1u32.to_be_bytes().to_vec();
+            payload.extend_from_slice(&0u32.to_be_bytes());
+            send(server, GOAWAY, 0, 0, &payload);
+            send(server, HEADERS, END_STREAM | END_HEADERS, 1, &[0x88]);
+        });
+
+        let first = conn.open_stream(request("GET"), 0).unwrap();
+        let second = conn.open_stream(request("GET"), 0).unwrap();
+        // the streams until the last stream of GOAWAY can finish
+        assert!(wait(|| conn.response(first)).is_ok());
+        let err = wait(|| conn.response(second)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        assert!(!conn.check());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn closed() {
+        let (mut conn, server) = connect(|_| {});
+        server.join().unwrap();
+
+        wait(|| {
+            if conn.check() {
+                Err(io::ErrorKind::WouldBlock.into())
+            } else {
+                Ok(())
+            }
+        })
+        .unwrap();
+        assert!(conn.closed.is_some());
+        assert!(conn.open_stream(request("GET"), 0).is_err());
+    }
+
+    #[test]
+    fn evicted_on_reuse() {
+        let (goaway, recv) = mpsc::channel::<()>();
+        let (conn, server) = connect(move |server| {
+            recv.recv().unwrap();
+            let mut payload = 0u32.to_be_bytes().to_vec();
+            payload.extend_from_slice(&0u32.to_be_bytes());
+            send(server, GOAWAY, 0, 0, &payload);
+            // is closed after the client has the GOAWAY
+            let _ = server.read(&mut [0; 1]);
+        });
+
+        let url = Url::parse("https://h2-evicted.test/").unwrap();
+        let key = PoolKey::new(&url, 443, &ConnectOptions::default());
+        register(key.clone(), conn);
+        assert!(get(&key).is_some());
+
+        goaway.send(()).unwrap();
+        wait(|| match get(&key) {
+            Some(_) => Err(io::ErrorKind::WouldBlock.into()),
+            None => Ok(()),
+        })
+        .unwrap();
+        assert!(!connections().lock().unwrap().contains_key(&key));
+
+        drop(goaway);
+        server.join().unwrap();
+    }
+}
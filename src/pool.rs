@@ -0,0 +1,206 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use url::Url;
+
+use crate::{
+    connection::Connection,
+    creating_connection::{ConnectOptions, TlsMode},
+    response::Headers,
+};
+
+/// If the server doesn't say how much a idle connection is kept
+const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(15);
+/// Max idle connections for the same host
+const MAX_IDLE: usize = 8;
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct PoolKey {
+    scheme: String,
+    host: String,
+    port: u16,
+    /// A connection thru a proxy is not the same as a direct one
+    proxy: Option<String>,
+    /// A connection verified with other roots or with a other client certificate is not reused
+    tls: TlsMode,
+    sni: Option<String>,
+    insecure_skip_verify: bool,
+    ca_files: Vec<String>,
+    system_certs: bool,
+    client_cert: Option<(String, String)>,
+}
+
+impl PoolKey {
+    pub fn new(url: &Url, port: u16, options: &ConnectOptions) -> Self {
+        let tls_options = &options.tls_options;
+        Self {
+            scheme: url.scheme().to_owned(),
+            host: url.host_str().unwrap_or_default().to_lowercase(),
+            port,
+            proxy: options.proxy.as_ref().map(|proxy| proxy.url.to_string()),
+            tls: options.tls,
+            sni: options.sni.clone(),
+            insecure_skip_verify: tls_options.insecure_skip_verify,
+            ca_files: tls_options.ca_files.clone(),
+            system_certs: tls_options.system_certs,
+            client_cert: tls_options.client_cert.clone(),
+        }
+    }
+}
+
+struct Idle {
+    conn: Connection,
+    expires: Instant,
+}
+
+/// Is added in the element storage when the connection can be returned to the pool
+/// after the response was fully readed
+pub struct Reusable {
+    pub key: PoolKey,
+    pub keep_alive: Duration,
+}
+
+fn pool() -> &'static Mutex<HashMap<PoolKey, Vec<Idle>>> {
+    static POOL: OnceLock<Mutex<HashMap<PoolKey, Vec<Idle>>>> = OnceLock::new();
+    POOL.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Takes a idle connection for `key` if has one that is not expired
+pub fn take(key: &PoolKey) -> Option<Connection> {
+    let mut pool = pool().lock().unwrap();
+    let idle = pool.get_mut(key)?;
+    let now = Instant::now();
+    idle.retain(|idle| idle.expires > now);
+
+    let conn = idle.pop().map(|idle| idle.conn);
+    if idle.is_empty() {
+        pool.remove(key);
+    }
+    conn
+}
+
+/// Gives back a connection that has no more response to read
+pub fn put(reusable: Reusable, conn: Connection) {
+    let mut pool = pool().lock().unwrap();
+    let idle = pool.entry(reusable.key).or_default();
+    let now = Instant::now();
+    idle.retain(|idle| idle.expires > now);
+
+    if idle.len() >= MAX_IDLE {
+        return;
+    }
+
+    log::info!("Connection returned to the pool");
+    idle.push(Idle {
+        conn,
+        expires: now + reusable.keep_alive,
+    });
+}
+
+/// From the response headers, None if the connection should be closed
+/// else how much can be idle
+pub fn keep_alive(headers: &Headers) -> Option<Duration> {
+    if headers.contains_token("Connection", "close") {
+        return None;
+    }
+
+    // Keep-Alive: timeout=5, max=100
+    let mut keep_alive = DEFAULT_KEEP_ALIVE;
+    if let Some(params) = headers.get("Keep-Alive") {
+        for param in params.split(',') {
+            let Some((name, value)) = param.split_once('=') else {
+                continue;
+            };
+            match name.trim().to_lowercase().as_str() {
+                "timeout" => {
+                    if let Ok(timeout) = value.trim().parse::<u64>() {
+                        // a little before the server will close it
+                        keep_alive =
+                            Duration::from_secs(timeout).saturating_sub(Duration::from_secs(1));
+                    }
+                }
+                "max" if value.trim() == "0" => return None,
+                _ => {}
+            }
+        }
+    }
+
+    Some(keep_alive)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, TcpStream};
+
+    use super::*;
+
+    fn headers(headers: &[(&str, &str)]) -> Headers {
+        Headers::new(
+            headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn keep_alive_headers() {
+        assert_eq!(keep_alive(&headers(&[])), Some(DEFAULT_KEEP_ALIVE));
+        assert_eq!(keep_alive(&headers(&[("Connection", "close")])), None);
+        assert_eq!(
+            keep_alive(&headers(&[("Connection", "Keep-Alive, Close")])),
+            None
+        );
+        assert_eq!(
+            keep_alive(&headers(&[("Keep-Alive", "timeout=5, max=100")])),
+            Some(Duration::from_secs(4))
+        );
+        assert_eq!(
+            keep_alive(&headers(&[("Keep-Alive", " Timeout = 0 ")])),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(
+            keep_alive(&headers(&[("Keep-Alive", "timeout=5, max=0")])),
+            None
+        );
+        assert_eq!(
+            keep_alive(&headers(&[("Keep-Alive", "timeout=abc, other")])),
+            Some(DEFAULT_KEEP_ALIVE)
+        );
+    }
+
+    #[test]
+    fn limits() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let conn = || Connection::TCP(TcpStream::connect(addr).unwrap(), Vec::new());
+
+        let url = Url::parse("http://pool-limits.test/").unwrap();
+        let key = PoolKey::new(&url, 80, &ConnectOptions::default());
+        let reusable = |keep_alive| Reusable {
+            key: key.clone(),
+            keep_alive,
+        };
+
+        for _ in 0..MAX_IDLE + 2 {
+            put(reusable(Duration::from_secs(60)), conn());
+        }
+        let mut taken = 0;
+        while take(&key).is_some() {
+            taken += 1;
+        }
+        assert_eq!(taken, MAX_IDLE);
+
+        // a expired connection is not given
+        put(reusable(Duration::ZERO), conn());
+        assert!(take(&key).is_none());
+
+        let other = Url::parse("https://pool-limits.test/").unwrap();
+        put(reusable(Duration::from_secs(60)), conn());
+        assert!(take(&PoolKey::new(&other, 443, &ConnectOptions::default())).is_none());
+        assert!(take(&key).is_some());
+    }
+}
@@ -0,0 +1,495 @@
+use std::{collections::HashMap, fmt::Display, io::Read};
+
+/// What to do with a header value continued on the next line (obs-fold, RFC 7230 3.2.4)
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ObsFold {
+    Reject,
+    /// The fold is replaced with a space
+    Replace,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ParseOptions {
+    /// Max size of the status line and the headers together
+    pub max_size: usize,
+    pub obs_fold: ObsFold,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        Self {
+            max_size: 64 * 1024,
+            obs_fold: ObsFold::Replace,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseError {
+    /// The connection was closed before anything was recived
+    Closed,
+    /// The connection was closed in the middle of the headers
+    Incomplete,
+    TooLarge(usize),
+    InvalidStatusLine(String),
+    InvalidHeader(String),
+    ObsFold(String),
+    /// Nothing was recived for the read timeout
+    Timeout,
+    Io(std::io::Error),
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::Closed => write!(f, "Connection closed before response"),
+            ParseError::Incomplete => write!(f, "Connection closed in the middle of the headers"),
+            ParseError::TooLarge(max) => {
+                write!(f, "Response headers are bigger then {} bytes", max)
+            }
+            ParseError::InvalidStatusLine(line) => write!(f, "Invalid status line: {:?}", line),
+            ParseError::InvalidHeader(line) => write!(f, "Invalid header: {:?}", line),
+            ParseError::ObsFold(line) => write!(f, "Folded header is not accepted: {:?}", line),
+            ParseError::Timeout => write!(f, "Timed out waiting for the response headers"),
+            ParseError::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// Response headers in the order that was recived, the names are case-insensitive
+#[derive(Clone, Default, Debug)]
+pub struct Headers(Vec<(String, String)>);
+
+impl Headers {
+    pub fn new(headers: Vec<(String, String)>) -> Self {
+        Self(headers)
+    }
+
+    /// The first value with `name`
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Every value with `name` like `Set-Cookie`
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.0
+            .iter()
+            .filter(move |(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// If `name` has `token` in his comma separated list, case-insensitive
+    pub fn contains_token(&self, name: &str, token: &str) -> bool {
+        self.get_all(name)
+            .flat_map(|value| value.split(','))
+            .any(|value| value.trim().eq_ignore_ascii_case(token))
+    }
+
+    /// For the `headers` value, the repeated headers are joined with `, `
+    /// `Set-Cookie` cannot be joined with `,` because of `Expires` and is joined with new lines
+    pub fn to_map(&self) -> HashMap<String, String> {
+        let mut map: HashMap<String, String> = HashMap::new();
+        for (name, value) in self.0.iter() {
+            let key = map
+                .keys()
+                .find(|key| key.eq_ignore_ascii_case(name))
+                .cloned()
+                .unwrap_or_else(|| name.clone());
+
+            if let Some(old) = map.get_mut(&key) {
+                if name.eq_ignore_ascii_case("Set-Cookie") {
+                    old.push('\n');
+                } else {
+                    old.push_str(", ");
+                }
+                old.push_str(value);
+            } else {
+                map.insert(key, value.clone());
+            }
+        }
+        map
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ResponseHead {
+    /// Like `HTTP/1.1`
+    pub version: String,
+    pub status: u16,
+    pub reason: String,
+    pub headers: Headers,
+}
+
+/// Reads until the end of the headers, nothing from the body is readed
+pub fn read_response_head(
+    reader: &mut impl Read,
+    options: &ParseOptions,
+) -> Result<ResponseHead, ParseError> {
+    // only a non-blocking reader can stop before the end
+    HeadReader::default()
+        .read(reader, options)?
+        .ok_or(ParseError::Timeout)
+}
+
+/// The response head readed a bit every tick from a non-blocking connection
+#[derive(Default)]
+pub struct HeadReader {
+    head: Vec<u8>,
+}
+
+impl HeadReader {
+    /// None if the end of the headers was not recived yet, after a head is returned is empty again
+    pub fn read(
+        &mut self,
+        reader: &mut impl Read,
+        options: &ParseOptions,
+    ) -> Result<Option<ResponseHead>, ParseError> {
+        let mut byte = [0; 1];
+
+        loop {
+            match reader.read(&mut byte) {
+                Ok(0) => {
+                    if self.head.is_empty() {
+                        return Err(ParseError::Closed);
+                    }
+                    return Err(ParseError::Incomplete);
+                }
+                Ok(_) => {
+                    // empty lines before the status line should be ignored
+                    if self.head.is_empty() && (byte[0] == b'\r' || byte[0] == b'\n') {
+                        continue;
+                    }
+
+                    self.head.push(byte[0]);
+                    if self.head.len() > options.max_size {
+                        return Err(ParseError::TooLarge(options.max_size));
+                    }
+                    if self.head.ends_with(b"\r\n\r\n") || self.head.ends_with(b"\n\n") {
+                        break;
+                    }
+                }
+                Err(err) => match err.kind() {
+                    std::io::ErrorKind::Interrupted => {}
+                    std::io::ErrorKind::WouldBlock => return Ok(None),
+                    // a blocking socket with a read timeout
+                    std::io::ErrorKind::TimedOut => return Err(ParseError::Timeout),
+                    _ => return Err(ParseError::Io(err)),
+                },
+            }
+        }
+
+        let head = std::mem::take(&mut self.head);
+        parse_response_head(&head, options).map(Some)
+    }
+}
+
+/// Parses the status line and the headers, `bytes` can end with the empty line or not
+pub fn parse_response_head(
+    bytes: &[u8],
+    options: &ParseOptions,
+) -> Result<ResponseHead, ParseError> {
+    if bytes.len() > options.max_size {
+        return Err(ParseError::TooLarge(options.max_size));
+    }
+
+    let text = String::from_utf8_lossy(bytes);
+    let mut lines = text
+        .split('\n')
+        .map(|line| line.strip_suffix('\r').unwrap_or(line));
+
+    let status_line = lines.next().unwrap_or_default();
+    let (version, status, reason) = parse_status_line(status_line)?;
+
+    let mut headers: Vec<(String, String)> = Vec::new();
+    for line in lines {
+        if line.is_empty() {
+            break;
+        }
+
+        if line.starts_with(' ') || line.starts_with('\t') {
+            let Some(last) = headers.last_mut() else {
+                return Err(ParseError::InvalidHeader(line.to_string()));
+            };
+            match options.obs_fold {
+                ObsFold::Reject => return Err(ParseError::ObsFold(line.to_string())),
+                ObsFold::Replace => {
+                    last.1.push(' ');
+                    last.1.push_str(line.trim());
+                    continue;
+                }
+            }
+        }
+
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(ParseError::InvalidHeader(line.to_string()));
+        };
+
+        // no whitespace is allowed between the name and the colon
+        if name.is_empty() || !name.bytes().all(is_token) {
+            return Err(ParseError::InvalidHeader(line.to_string()));
+        }
+
+        headers.push((name.to_string(), value.trim().to_string()));
+    }
+
+    Ok(ResponseHead {
+        version,
+        status,
+        reason,
+        headers: Headers::new(headers),
+    })
+}
+
+/// `HTTP/1.1 200 OK`, the reason can be empty
+fn parse_status_line(line: &str) -> Result<(String, u16, String), ParseError> {
+    let invalid = || ParseError::InvalidStatusLine(line.to_string());
+
+    let (version, rest) = line.split_once(' ').ok_or_else(invalid)?;
+    let (status, reason) = rest.split_once(' ').unwrap_or((rest, ""));
+
+    let valid_version = version
+        .strip_prefix("HTTP/")
+        .map(|v| {
+            let v = v.as_bytes();
+            v.len() == 3 && v[0].is_ascii_digit() && v[1] == b'.' && v[2].is_ascii_digit()
+        })
+        .unwrap_or(false);
+    if !valid_version {
+        return Err(invalid());
+    }
+
+    if status.len() != 3 || !status.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(invalid());
+    }
+    let status = status.parse().map_err(|_| invalid())?;
+
+    Ok((version.to_string(), status, reason.trim().to_string()))
+}
+
+/// tchar from RFC 7230
+fn is_token(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Gives the bytes in small pieces like a slow socket
+    struct Split<'a> {
+        data: &'a [u8],
+        size: usize,
+    }
+
+    impl Read for Split<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let len = self.size.min(buf.len()).min(self.data.len());
+            buf[..len].copy_from_slice(&self.data[..len]);
+            self.data = &self.data[len..];
+            Ok(len)
+        }
+    }
+
+    fn read(data: &[u8], options: &ParseOptions) -> Result<ResponseHead, ParseError> {
+        read_response_head(&mut Split { data, size: 3 }, options)
+    }
+
+    #[test]
+    fn split_reads() {
+        let mut reader = Split {
+            data: b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello",
+            size: 1,
+        };
+        let head = read_response_head(&mut reader, &ParseOptions::default()).unwrap();
+        assert_eq!(head.version, "HTTP/1.1");
+        assert_eq!(head.status, 200);
+        assert_eq!(head.reason, "OK");
+        assert_eq!(head.headers.get("content-length"), Some("5"));
+        // the body is not readed
+        assert_eq!(reader.data, b"hello");
+    }
+
+    /// Like a non-blocking socket, every second read has nothing
+    struct Blocking<'a> {
+        data: &'a [u8],
+        ready: bool,
+    }
+
+    impl Read for Blocking<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.ready = !self.ready;
+            if !self.ready {
+                return Err(std::io::ErrorKind::WouldBlock.into());
+            }
+            let len = buf.len().min(1).min(self.data.len());
+            buf[..len].copy_from_slice(&self.data[..len]);
+            self.data = &self.data[len..];
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn non_blocking() {
+        let mut reader = Blocking {
+            data: b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 204 No Content\r\n\r\n",
+            ready: false,
+        };
+        let mut head = HeadReader::default();
+        let options = ParseOptions::default();
+
+        let mut responses = Vec::new();
+        let mut pending = 0;
+        while responses.len() < 2 {
+            match head.read(&mut reader, &options).unwrap() {
+                Some(response) => responses.push(response.status),
+                None => pending += 1,
+            }
+        }
+        assert_eq!(responses, [100, 204]);
+        assert!(pending > 0);
+
+        // a blocking reader cannot be continued
+        assert!(matches!(
+            read_response_head(
+                &mut Blocking {
+                    data: b"HTTP/1.1",
+                    ready: true
+                },
+                &options
+            ),
+            Err(ParseError::Timeout)
+        ));
+    }
+
+    #[test]
+    fn bare_lf_and_empty_lines_before() {
+        let head = read(
+            b"\r\n\nHTTP/1.0 404 Not Found\nServer: test\n\n",
+            &ParseOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(head.status, 404);
+        assert_eq!(head.reason, "Not Found");
+        assert_eq!(head.headers.get("Server"), Some("test"));
+    }
+
+    #[test]
+    fn empty_reason() {
+        let head = read(b"HTTP/1.1 204\r\n\r\n", &ParseOptions::default()).unwrap();
+        assert_eq!(head.status, 204);
+        assert_eq!(head.reason, "");
+    }
+
+    #[test]
+    fn repeated_headers() {
+        let head = read(
+            b"HTTP/1.1 200 OK\r\nVary: Accept\r\nSet-Cookie: a=1; Expires=Wed, 21 Oct 2015 07:28:00 GMT\r\nvary: Cookie\r\nSet-Cookie: b=2\r\nTransfer-Encoding: gzip, Chunked\r\n\r\n",
+            &ParseOptions::default(),
+        )
+        .unwrap();
+        let headers = &head.headers;
+        assert_eq!(headers.get("Vary"), Some("Accept"));
+        assert_eq!(
+            headers.get_all("VARY").collect::<Vec<_>>(),
+            ["Accept", "Cookie"]
+        );
+        assert!(headers.contains_token("Transfer-Encoding", "chunked"));
+        assert!(!headers.contains_token("Transfer-Encoding", "deflate"));
+
+        let map = headers.to_map();
+        assert_eq!(map.get("Vary").map(String::as_str), Some("Accept, Cookie"));
+        assert_eq!(
+            map.get("Set-Cookie").map(String::as_str),
+            Some("a=1; Expires=Wed, 21 Oct 2015 07:28:00 GMT\nb=2")
+        );
+    }
+
+    #[test]
+    fn folded_headers() {
+        let data = b"HTTP/1.1 200 OK\r\nX-Long: first\r\n  second\r\n\tthird\r\n\r\n";
+
+        let head = read(data, &ParseOptions::default()).unwrap();
+        assert_eq!(head.headers.get("X-Long"), Some("first second third"));
+
+        let options = ParseOptions {
+            obs_fold: ObsFold::Reject,
+            ..Default::default()
+        };
+        assert!(matches!(read(data, &options), Err(ParseError::ObsFold(_))));
+
+        // a fold without a header before
+        assert!(matches!(
+            read(
+                b"HTTP/1.1 200 OK\r\n folded\r\n\r\n",
+                &ParseOptions::default()
+            ),
+            Err(ParseError::InvalidHeader(_))
+        ));
+    }
+
+    #[test]
+    fn size_limit() {
+        let options = ParseOptions {
+            max_size: 32,
+            ..Default::default()
+        };
+        assert!(read(b"HTTP/1.1 200 OK\r\nA: b\r\n\r\n", &options).is_ok());
+        assert!(matches!(
+            read(b"HTTP/1.1 200 OK\r\nX-Big: 0123456789\r\n\r\n", &options),
+            Err(ParseError::TooLarge(32))
+        ));
+        assert!(matches!(
+            parse_response_head(&[b'a'; 33], &options),
+            Err(ParseError::TooLarge(32))
+        ));
+    }
+
+    #[test]
+    fn closed_and_incomplete() {
+        let options = ParseOptions::default();
+        assert!(matches!(read(b"", &options), Err(ParseError::Closed)));
+        assert!(matches!(read(b"\r\n", &options), Err(ParseError::Closed)));
+        assert!(matches!(
+            read(b"HTTP/1.1 200 OK\r\nA: b\r\n", &options),
+            Err(ParseError::Incomplete)
+        ));
+    }
+
+    #[test]
+    fn invalid() {
+        let options = ParseOptions::default();
+        for line in [
+            "HTTP/1.1 20 OK",
+            "HTTP/1.1 2000 OK",
+            "HTTP/11 200 OK",
+            "ICY 200 OK",
+            "HTTP/1.1",
+        ] {
+            assert!(
+                matches!(
+                    read(format!("{}\r\n\r\n", line).as_bytes(), &options),
+                    Err(ParseError::InvalidStatusLine(_))
+                ),
+                "{}",
+                line
+            );
+        }
+
+        for header in ["Name : value", "no colon", ": empty name", "Bad\"Name: 1"] {
+            assert!(
+                matches!(
+                    read(
+                        format!("HTTP/1.1 200 OK\r\n{}\r\n\r\n", header).as_bytes(),
+                        &options
+                    ),
+                    Err(ParseError::InvalidHeader(_))
+                ),
+                "{}",
+                header
+            );
+        }
+    }
+}
@@ -0,0 +1,39 @@
+use muzzman_lib::prelude::*;
+
+use crate::{
+    chunked::ChunkedDecoder,
+    connection::Connection,
+    content_encoding::ContentDecoder,
+    creating_connection::{creating_connection, Request},
+    pool::Reusable,
+    segments::Segments,
+    websocket::WebSocket,
+};
+
+/// Drops the old connection and connects again,
+/// `creating_connection` will ask for the range from `recv`
+/// a segmented download will continue on a single connection
+pub fn resuming(element: &ERow, storage: &mut Storage) -> Result<(), SessionError> {
+    storage.remove::<Connection>();
+    storage.remove::<Request>();
+    storage.remove::<ChunkedDecoder>();
+    storage.remove::<ContentDecoder>();
+    storage.remove::<Reusable>();
+    storage.remove::<WebSocket>();
+
+    // the segments are not continuous, resume from the first hole
+    let downloaded_until = storage
+        .remove::<Segments>()
+        .map(|segments| segments.downloaded_until());
+
+    {
+        let mut element = element.write().unwrap();
+        if let Some(downloaded_until) = downloaded_until {
+            element.settings.set("recv", Type::USize(downloaded_until));
+        }
+        element.settings.set("redirects", Type::USize(0));
+        element.settings.set("sent", Type::USize(0));
+    }
+
+    creating_connection(element, storage)
+}
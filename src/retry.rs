@@ -0,0 +1,244 @@
+use std::{
+    fmt::Display,
+    io::{Seek, SeekFrom},
+    time::{Duration, Instant, SystemTime},
+};
+
+use muzzman_lib::prelude::*;
+
+use crate::{cookies::parse_cookie_date, error, response::Headers};
+
+/// Status when waiting for the next attempt
+pub const RETRYING: usize = 10;
+
+/// Is in the element storage, the attempts are counted for all the download
+pub struct Retry {
+    pub attempt: usize,
+    at: Instant,
+}
+
+pub struct RetryOptions {
+    pub max_retries: usize,
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    pub statuses: Vec<u16>,
+}
+
+impl RetryOptions {
+    /// `backoff * 2^(attempt - 1)` but max `max_backoff`
+    pub fn backoff(&self, attempt: usize) -> Duration {
+        let factor = 1u32
+            .checked_shl(attempt.saturating_sub(1) as u32)
+            .unwrap_or(u32::MAX);
+        self.backoff
+            .checked_mul(factor)
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff)
+    }
+}
+
+/// `max-retries`, `retry-backoff` and `retry-max-backoff` in milliseconds,
+/// `retry-status` like `408,429,500,502,503,504`
+pub fn get_retry_options(element: &ERow) -> RetryOptions {
+    let element = element.read().unwrap();
+    let get = |name: &str, default: usize| match element.element_data.get(name) {
+        Some(Type::USize(value)) => *value,
+        _ => default,
+    };
+
+    let statuses = match element.element_data.get("retry-status") {
+        Some(Type::String(statuses)) => statuses
+            .split([',', ';', ' '])
+            .filter_map(|status| status.trim().parse().ok())
+            .collect(),
+        _ => vec![408, 429, 500, 502, 503, 504],
+    };
+
+    RetryOptions {
+        max_retries: get("max-retries", 5),
+        backoff: Duration::from_millis(get("retry-backoff", 1000) as u64),
+        max_backoff: Duration::from_millis(get("retry-max-backoff", 60000) as u64),
+        statuses,
+    }
+}
+
+pub fn is_retryable_status(element: &ERow, status: u16) -> bool {
+    get_retry_options(element).statuses.contains(&status)
+}
+
+/// `Retry-After` as seconds or as a HTTP-date
+pub fn retry_after(headers: &Headers) -> Option<Duration> {
+    let value = headers.get("Retry-After")?.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    let date = parse_cookie_date(value)?;
+    // a date in the past is now
+    Some(
+        date.duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+/// Why a connection failed, only a transient failure is retried
+#[derive(Debug, PartialEq, Eq)]
+pub enum Failure {
+    /// Like a reset, a timeout or a dns error
+    Transient(String),
+    /// Like a invalid certificate or a wrong proxy, the next attempt will fail the same
+    Permanent(String),
+}
+
+impl Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Failure::Transient(reason) | Failure::Permanent(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+/// A error without a kind is transient
+impl From<String> for Failure {
+    fn from(reason: String) -> Self {
+        Failure::Transient(reason)
+    }
+}
+
+impl From<Failure> for String {
+    fn from(failure: Failure) -> Self {
+        failure.to_string()
+    }
+}
+
+/// `failed` if is transient, else is a error directly
+pub fn failed_with(
+    element: &ERow,
+    storage: &mut Storage,
+    failure: Failure,
+) -> Result<(), SessionError> {
+    match failure {
+        Failure::Transient(reason) => failed(element, storage, reason, None),
+        Failure::Permanent(reason) => Err(error(element, reason)),
+    }
+}
+
+/// A transient failure, like a connection reset or a 503
+/// if has more attempts waits and then resumes, else is a error
+/// `retry_after` is from the server and is used instead of the backoff
+pub fn failed(
+    element: &ERow,
+    storage: &mut Storage,
+    reason: impl Into<String>,
+    retry_after: Option<Duration>,
+) -> Result<(), SessionError> {
+    let reason = reason.into();
+    let options = get_retry_options(element);
+    let attempt = storage
+        .get::<Retry>()
+        .map(|retry| retry.attempt)
+        .unwrap_or(0)
+        + 1;
+
+    if attempt > options.max_retries {
+        if options.max_retries == 0 {
+            return Err(error(element, reason));
+        }
+        return Err(error(
+            element,
+            format!("{} (after {} retries)", reason, options.max_retries),
+        ));
+    }
+
+    let wait = retry_after.unwrap_or_else(|| options.backoff(attempt));
+    log::warn!(
+        "{}, retrying in {:.1}s, attempt {} of {}",
+        reason,
+        wait.as_secs_f64(),
+        attempt,
+        options.max_retries
+    );
+
+    storage.set(Retry {
+        attempt,
+        at: Instant::now() + wait,
+    });
+    element.write().unwrap().statuses[RETRYING] = format!(
+        "Retrying in {}s, attempt {} of {}: {}",
+        wait.as_secs_f64().ceil(),
+        attempt,
+        options.max_retries,
+        reason
+    );
+    element.set_status(RETRYING);
+    Ok(())
+}
+
+/// After a response or on a new download the attempts are counted again from 0
+pub fn reset(storage: &mut Storage) {
+    storage.remove::<Retry>();
+}
+
+/// Waits for the backoff, is not sleeping but checked every tick, then the element is resumed from `recv`
+pub fn waiting(element: &ERow, storage: &mut Storage) -> Result<(), SessionError> {
+    if let Some(retry) = storage.get::<Retry>() {
+        if Instant::now() < retry.at {
+            return Ok(());
+        }
+
+        let max_retries = get_retry_options(element).max_retries;
+        element.write().unwrap().statuses[1] = format!(
+            "Negotieiting Connection, attempt {} of {}",
+            retry.attempt, max_retries
+        );
+    }
+
+    // the body is sent again from the start
+    if let Some(Type::FileOrData(body)) = element.write().unwrap().element_data.get_mut("body") {
+        let _ = body.seek(SeekFrom::Start(0));
+    }
+
+    element.set_status(6);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(value: &str) -> Headers {
+        Headers::new(vec![("Retry-After".to_string(), value.to_string())])
+    }
+
+    #[test]
+    fn retry_after_header() {
+        assert_eq!(retry_after(&headers("120")), Some(Duration::from_secs(120)));
+        assert_eq!(retry_after(&headers(" 0 ")), Some(Duration::ZERO));
+        assert_eq!(
+            retry_after(&headers("Sun, 06 Nov 1994 08:49:37 GMT")),
+            Some(Duration::ZERO)
+        );
+        assert!(retry_after(&headers("Fri, 31 Dec 9999 23:59:59 GMT")).unwrap() > Duration::ZERO);
+        assert_eq!(retry_after(&headers("soon")), None);
+        assert_eq!(retry_after(&headers("-1")), None);
+        assert_eq!(retry_after(&Headers::new(vec![])), None);
+    }
+
+    #[test]
+    fn backoff() {
+        let options = RetryOptions {
+            max_retries: 5,
+            backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            statuses: vec![],
+        };
+        assert_eq!(options.backoff(1), Duration::from_secs(1));
+        assert_eq!(options.backoff(2), Duration::from_secs(2));
+        assert_eq!(options.backoff(5), Duration::from_secs(16));
+        assert_eq!(options.backoff(7), Duration::from_secs(60));
+        assert_eq!(options.backoff(100), Duration::from_secs(60));
+        assert_eq!(options.backoff(usize::MAX), Duration::from_secs(60));
+        // the first attempt when there was no attempt before
+        assert_eq!(options.backoff(0), Duration::from_secs(1));
+    }
+}
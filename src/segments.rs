@@ -0,0 +1,526 @@
+use std::{
+    collections::HashMap,
+    io::{Read, Seek, SeekFrom, Write},
+    time::Instant,
+};
+
+use muzzman_lib::prelude::*;
+use url::Url;
+
+use crate::{
+    auth::Auth,
+    checksum,
+    connection::Connection,
+    cookies,
+    creating_connection::{
+        get_headers, get_parse_options, get_url, get_validator, open_connection,
+        parse_content_range, read_response, send_request, ConnectOptions, Opening,
+    },
+    error,
+    request::RequestTarget,
+    response::{HeadReader, ParseOptions},
+    retry,
+    throttle::{self, Direction},
+    timeouts,
+    uploading::get_buffer_size,
+};
+
+/// A segment smaller then this will not be splited
+const MIN_SEGMENT: usize = 256 * 1024;
+
+/// A byte range of the file downloaded by his own connection
+pub struct Segment {
+    conn: Connection,
+    /// Where the next byte will be writen in `element.data`
+    pos: usize,
+    /// Exclusive
+    end: usize,
+}
+
+impl Segment {
+    fn remaining(&self) -> usize {
+        self.end - self.pos
+    }
+}
+
+/// Segmented download, every segment is downloaded in parallel at his offset
+/// when a connection finishes early the biggest remaining segment is splited in two
+pub struct Segments {
+    url: Url,
+    port: u16,
+    host: String,
+    connect_options: ConnectOptions,
+    segments: Vec<Segment>,
+    content_length: usize,
+    /// How many connections are wanted, is lowered when the server refuses one more
+    connections: usize,
+    /// Only one new segment at a time
+    pending: Option<Pending>,
+}
+
+impl Segments {
+    /// `conn` is the first connection that is already downloading from 0
+    pub fn new(
+        url: Url,
+        port: u16,
+        host: String,
+        connect_options: ConnectOptions,
+        conn: Connection,
+        content_length: usize,
+        connections: usize,
+    ) -> Self {
+        Self {
+            url,
+            port,
+            host,
+            connect_options,
+            segments: vec![Segment {
+                conn,
+                pos: 0,
+                end: content_length,
+            }],
+            content_length,
+            connections,
+            pending: None,
+        }
+    }
+
+    /// From where is not downloaded continuously, everything before was downloaded
+    pub fn downloaded_until(&self) -> usize {
+        self.segments
+            .iter()
+            .map(|segment| segment.pos)
+            .min()
+            .unwrap_or(self.content_length)
+    }
+
+    fn recived(&self) -> usize {
+        self.content_length
+            - self
+                .segments
+                .iter()
+                .map(|segment| segment.remaining())
+                .sum::<usize>()
+    }
+
+    /// Starts a connection for the second half of the biggest segment
+    fn split(
+        &mut self,
+        headers: HashMap<String, String>,
+        auth: Option<&Auth>,
+        options: ParseOptions,
+    ) -> Result<(), String> {
+        let Some(biggest) = self
+            .segments
+            .iter()
+            .max_by_key(|segment| segment.remaining())
+        else {
+            return Ok(());
+        };
+
+        // the segments are only getting smaller
+        if biggest.remaining() < MIN_SEGMENT * 2 {
+            self.connections = self.segments.len();
+            return Ok(());
+        }
+
+        let start = biggest.pos + biggest.remaining() / 2;
+        let end = biggest.end;
+
+        let mut headers = headers;
+        headers.insert("Range".to_string(), format!("bytes={}-{}", start, end - 1));
+
+        let opening = open_connection(&self.url, self.port, &self.connect_options)?;
+        self.connect_options.proxy_headers(&self.url, &mut headers);
+        let target = self
+            .connect_options
+            .request_target(&self.url, RequestTarget::Origin)
+            .build(&self.url, self.port);
+        if !headers
+            .keys()
+            .any(|name| name.eq_ignore_ascii_case("Authorization"))
+        {
+            if let Some(authorization) = auth
+                .map(|auth| auth.authorization(&self.url, "GET", &target))
+                .transpose()?
+                .flatten()
+            {
+                headers.insert("Authorization".to_string(), authorization);
+            }
+        }
+
+        self.pending = Some(Pending {
+            step: PendingStep::Opening(Box::new(opening), target, headers),
+            options,
+            start,
+            end,
+        });
+        self.connecting()
+    }
+
+    /// A step of the new segment, when the response is valid the biggest segment is splited
+    fn connecting(&mut self) -> Result<(), String> {
+        let Some(pending) = &mut self.pending else {
+            return Ok(());
+        };
+
+        if let PendingStep::Opening(opening, target, headers) = &mut pending.step {
+            let Some(mut conn) = opening.poll()? else {
+                return Ok(());
+            };
+            send_request(&mut conn, &self.url, "GET", target, &self.host, headers)?;
+            pending.step =
+                PendingStep::Reading(Box::new(conn), HeadReader::default(), Instant::now());
+        }
+
+        let PendingStep::Reading(conn, head, since) = &mut pending.step else {
+            return Ok(());
+        };
+        let Some(response) = read_response(conn, head, &pending.options)? else {
+            if self
+                .connect_options
+                .timeouts
+                .read
+                .is_some_and(|timeout| since.elapsed() >= timeout)
+            {
+                return Err("Segment: timed out waiting for the response headers".to_string());
+            }
+            return Ok(());
+        };
+
+        let Some(Pending {
+            step: PendingStep::Reading(conn, ..),
+            start,
+            end,
+            ..
+        }) = self.pending.take()
+        else {
+            return Ok(());
+        };
+
+        if response.status != 206 {
+            return Err(format!(
+                "Segment: expected 206 Partial Content but got: {} {}",
+                response.status, response.reason
+            ));
+        }
+
+        // a other range or a file that changed its length would be writen in the wrong place
+        let range = response.headers.get("Content-Range").unwrap_or_default();
+        match parse_content_range(range) {
+            Some((range_start, range_end, total))
+                if range_start == start
+                    && range_end + 1 == end
+                    && total.is_none_or(|total| total == self.content_length) => {}
+            _ => return Err(format!("Segment: invalid Content-Range: {}", range)),
+        }
+
+        // while was connecting the segment could be downloaded after `start`
+        let Some(owner) = self
+            .segments
+            .iter_mut()
+            .find(|segment| segment.pos < start && segment.end == end)
+        else {
+            log::info!("Segment {}..{} was already downloaded", start, end);
+            return Ok(());
+        };
+
+        log::info!("New segment: {}..{}", start, end);
+
+        // the other connection will be droped when will reach the new end
+        owner.end = start;
+        self.segments.push(Segment {
+            conn: *conn,
+            pos: start,
+            end,
+        });
+        Ok(())
+    }
+}
+
+/// A segment that is connecting, a step every tick
+struct Pending {
+    step: PendingStep,
+    options: ParseOptions,
+    start: usize,
+    end: usize,
+}
+
+enum PendingStep {
+    /// The request target and the headers are sent when is connected
+    Opening(Box<Opening>, String, HashMap<String, String>),
+    /// Since when is waiting for the response
+    Reading(Box<Connection>, HeadReader, Instant),
+}
+
+pub fn downloading_segments(element: &ERow, storage: &mut Storage) -> Result<(), SessionError> {
+    let buffer_size = get_buffer_size(element)?;
+
+    // only one new connection at a time, the headers are needed only when is started
+    let mut split = None;
+    if storage.get::<Segments>().is_some_and(|segments| {
+        segments.pending.is_none() && segments.segments.len() < segments.connections
+    }) {
+        let mut headers = get_headers(element);
+        if let Some(validator) = get_validator(element) {
+            headers.insert("If-Range".to_string(), validator);
+        }
+
+        let url = get_url(element)?;
+        cookies::add_cookie_header(element, storage, &url, "GET", &mut headers);
+
+        split = Some((
+            headers,
+            storage.get::<Auth>().cloned(),
+            get_parse_options(element),
+        ));
+    }
+
+    // shared by all the segments in this tick
+    let mut allowance = {
+        let want = match storage.get::<Segments>() {
+            Some(segments) => buffer_size * segments.segments.len().max(1),
+            None => 0,
+        };
+        throttle::allowance(element, storage, Direction::Download, want)
+    };
+    let mut consumed = 0;
+
+    let Some(segments) = storage.get_mut::<Segments>() else {
+        return Ok(());
+    };
+
+    let res = match split {
+        Some((headers, auth, options)) => segments.split(headers, auth.as_ref(), options),
+        None => segments.connecting(),
+    };
+    if let Err(err) = res {
+        // like when the server has a limit of connections, the ones that are open are kept
+        segments.pending = None;
+        segments.connections = segments.segments.len();
+        log::warn!(
+            "Cannot add segment, continuing with {} connections: {}",
+            segments.connections,
+            err
+        );
+    }
+
+    let mut buffer = vec![0; buffer_size];
+    let mut i = 0;
+    while i < segments.segments.len() {
+        let segment = &mut segments.segments[i];
+        let want = buffer_size.min(segment.remaining()).min(allowance);
+        if want == 0 && segment.remaining() > 0 {
+            i += 1;
+            continue;
+        }
+
+        let len = match segment.conn.read(&mut buffer[0..want]) {
+            Ok(0) if want > 0 => None,
+            Ok(len) => Some(len),
+            Err(err) => match err.kind() {
+                std::io::ErrorKind::WouldBlock => Some(0),
+                _ => None,
+            },
+        };
+        // resuming will continue from the first hole
+        let Some(len) = len else {
+            return retry::failed(
+                element,
+                storage,
+                "Error: Connection close unexpected!",
+                None,
+            );
+        };
+
+        if len > 0 {
+            let mut element_w = element.write().unwrap();
+            let _ = element_w.data.seek(SeekFrom::Start(segment.pos as u64));
+            if let Err(err) = element_w.data.write_all(&buffer[0..len]) {
+                drop(element_w);
+                return Err(error(
+                    element,
+                    format!("Error: Cannot write the data: {}", err),
+                ));
+            }
+            segment.pos += len;
+            allowance -= len;
+            consumed += len;
+        }
+
+        if segment.remaining() == 0 {
+            segments.segments.remove(i);
+        } else {
+            i += 1;
+        }
+    }
+
+    let recived = segments.recived();
+    let content_length = segments.content_length;
+    let done = segments.segments.is_empty();
+
+    {
+        let mut element = element.write().unwrap();
+        element.settings.set("recv", Type::USize(recived));
+        element.progress = ((recived as f64) / (content_length as f64)) as f32;
+    }
+
+    throttle::consume(storage, Direction::Download, consumed);
+    if !done {
+        timeouts::check_stall(element, storage, consumed)?;
+    }
+
+    if done {
+        storage.remove::<Segments>();
+        let _ = element.write().unwrap().data.seek(SeekFrom::End(0));
+        return checksum::complete(element, storage);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{TcpListener, TcpStream},
+        thread::{self, JoinHandle},
+        time::Duration,
+    };
+
+    use super::*;
+
+    /// The first connection is from 0, `listener` gets the connections of the new segments
+    fn segments(content_length: usize) -> (Segments, TcpListener) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let url = Url::parse(&format!("http://{}/file", addr)).unwrap();
+        let conn = Connection::TCP(TcpStream::connect(addr).unwrap(), Vec::new());
+        listener.accept().unwrap();
+
+        let segments = Segments::new(
+            url,
+            addr.port(),
+            addr.to_string(),
+            ConnectOptions::default(),
+            conn,
+            content_length,
+            4,
+        );
+        (segments, listener)
+    }
+
+    /// Answers the next request with `content_range`, returns the `Range` that was asked
+    fn serve(listener: TcpListener, content_range: String) -> JoinHandle<String> {
+        thread::spawn(move || {
+            let (mut tcp, _) = listener.accept().unwrap();
+            let mut head = Vec::new();
+            let mut byte = [0; 1];
+            while !head.ends_with(b"\r\n\r\n") {
+                tcp.read_exact(&mut byte).unwrap();
+                head.push(byte[0]);
+            }
+            tcp.write_all(
+                format!(
+                    "HTTP/1.1 206 Partial Content\r\nContent-Range: {}\r\n\r\n",
+                    content_range
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+            // the connection is kept until the segment is added
+            let _ = tcp.read(&mut byte);
+
+            let head = String::from_utf8(head).unwrap();
+            head.lines()
+                .find_map(|line| line.strip_prefix("Range: "))
+                .unwrap_or_default()
+                .to_string()
+        })
+    }
+
+    /// Like every tick, until the new segment is added or refused
+    fn split(segments: &mut Segments) -> Result<(), String> {
+        segments.split(HashMap::new(), None, ParseOptions::default())?;
+        let start = Instant::now();
+        while segments.pending.is_some() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(1));
+            segments.connecting()?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn split_biggest() {
+        let length = MIN_SEGMENT * 4;
+        let (mut segments, listener) = segments(length);
+        let half = MIN_SEGMENT * 2;
+        let server = serve(
+            listener,
+            format!("bytes {}-{}/{}", half, length - 1, length),
+        );
+
+        split(&mut segments).unwrap();
+        let ranges = segments
+            .segments
+            .iter()
+            .map(|segment| (segment.pos, segment.end))
+            .collect::<Vec<_>>();
+        assert_eq!(ranges, [(0, half), (half, length)]);
+        assert_eq!(segments.downloaded_until(), 0);
+        assert_eq!(segments.recived(), 0);
+
+        // the second segment is downloading, the first is the hole
+        segments.segments[1].pos += 100;
+        segments.segments[0].pos += 10;
+        assert_eq!(segments.downloaded_until(), 10);
+        assert_eq!(segments.recived(), 110);
+
+        drop(segments);
+        assert_eq!(
+            server.join().unwrap(),
+            format!("bytes={}-{}", half, length - 1)
+        );
+    }
+
+    #[test]
+    fn min_segment() {
+        let (mut segments, _listener) = segments(MIN_SEGMENT * 2 - 1);
+        split(&mut segments).unwrap();
+        assert_eq!(segments.segments.len(), 1);
+        // no more connections are tried
+        assert_eq!(segments.connections, 1);
+        assert_eq!(segments.downloaded_until(), 0);
+
+        segments.segments.clear();
+        assert_eq!(segments.downloaded_until(), MIN_SEGMENT * 2 - 1);
+    }
+
+    #[test]
+    fn invalid_content_range() {
+        let length = MIN_SEGMENT * 4;
+        let half = MIN_SEGMENT * 2;
+        for range in [
+            format!("bytes {}-{}/{}", half + 1, length - 1, length),
+            format!("bytes {}-{}/{}", half, length - 2, length),
+            format!("bytes {}-{}/{}", half, length - 1, length + 1),
+            "bytes */1".to_string(),
+        ] {
+            let (mut segments, listener) = segments(length);
+            let server = serve(listener, range.clone());
+            assert!(split(&mut segments).is_err(), "{}", range);
+            assert_eq!(segments.segments.len(), 1);
+            assert_eq!(segments.segments[0].end, length);
+            drop(segments);
+            server.join().unwrap();
+        }
+
+        // the total can be unknown
+        let (mut segments, listener) = segments(length);
+        let server = serve(listener, format!("bytes {}-{}/*", half, length - 1));
+        split(&mut segments).unwrap();
+        assert_eq!(segments.segments.len(), 2);
+        drop(segments);
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,337 @@
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+};
+
+use url::Host;
+
+use crate::retry::Failure;
+
+const NO_AUTH: u8 = 0x00;
+const USER_PASSWORD: u8 = 0x02;
+const NO_ACCEPTABLE: u8 = 0xFF;
+
+const CONNECT: u8 = 0x01;
+
+const IPV4: u8 = 0x01;
+const DOMAIN: u8 = 0x03;
+const IPV6: u8 = 0x04;
+
+/// A io error is transient, a error from the proxy is permanent if the next attempt fails the same
+fn io_err(err: std::io::Error) -> Failure {
+    Failure::Transient(format!("Error: SOCKS: {}", err))
+}
+
+fn read_exact(tcp: &mut TcpStream, len: usize) -> Result<Vec<u8>, Failure> {
+    let mut buffer = vec![0; len];
+    tcp.read_exact(&mut buffer).map_err(io_err)?;
+    Ok(buffer)
+}
+
+/// SOCKS5 (RFC 1928) with no auth or user and password (RFC 1929)
+/// the domain is sent to the proxy and is resolved there
+pub fn socks5(
+    tcp: &mut TcpStream,
+    host: Host<&str>,
+    port: u16,
+    credentials: Option<(String, String)>,
+) -> Result<(), Failure> {
+    let mut greeting = vec![5, 1, NO_AUTH];
+    if credentials.is_some() {
+        greeting = vec![5, 2, NO_AUTH, USER_PASSWORD];
+    }
+    tcp.write_all(&greeting).map_err(io_err)?;
+
+    let choice = read_exact(tcp, 2)?;
+    if choice[0] != 5 {
+        return Err(Failure::Permanent(format!(
+            "Error: SOCKS: invalid version: {}",
+            choice[0]
+        )));
+    }
+
+    match choice[1] {
+        NO_AUTH => {}
+        USER_PASSWORD => {
+            let Some((user, password)) = credentials else {
+                return Err(Failure::Permanent(
+                    "Error: SOCKS: proxy wants a user and password".to_string(),
+                ));
+            };
+            if user.len() > 255 || password.len() > 255 {
+                return Err(Failure::Permanent(
+                    "Error: SOCKS: user or password is too long".to_string(),
+                ));
+            }
+
+            let mut auth = vec![1, user.len() as u8];
+            auth.extend_from_slice(user.as_bytes());
+            auth.push(password.len() as u8);
+            auth.extend_from_slice(password.as_bytes());
+            tcp.write_all(&auth).map_err(io_err)?;
+
+            let status = read_exact(tcp, 2)?;
+            if status[1] != 0 {
+                return Err(Failure::Permanent(
+                    "Error: SOCKS: authentication failed".to_string(),
+                ));
+            }
+        }
+        NO_ACCEPTABLE => {
+            return Err(Failure::Permanent(
+                "Error: SOCKS: no acceptable authentication method".to_string(),
+            ))
+        }
+        method => {
+            return Err(Failure::Permanent(format!(
+                "Error: SOCKS: unsupported authentication method: {}",
+                method
+            )))
+        }
+    }
+
+    let mut request = vec![5, CONNECT, 0];
+    match host {
+        Host::Domain(domain) => {
+            if domain.len() > 255 {
+                return Err(Failure::Permanent(
+                    "Error: SOCKS: host name is too long".to_string(),
+                ));
+            }
+            request.push(DOMAIN);
+            request.push(domain.len() as u8);
+            request.extend_from_slice(domain.as_bytes());
+        }
+        Host::Ipv4(ip) => {
+            request.push(IPV4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Host::Ipv6(ip) => {
+            request.push(IPV6);
+            request.extend_from_slice(&ip.octets());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    tcp.write_all(&request).map_err(io_err)?;
+
+    let reply = read_exact(tcp, 4)?;
+    if reply[1] != 0 {
+        let reason = format!("Error: SOCKS: {}", reply_error(reply[1]));
+        // not allowed or not supported is the same on the next attempt
+        return Err(match reply[1] {
+            0x02 | 0x07 | 0x08 => Failure::Permanent(reason),
+            _ => Failure::Transient(reason),
+        });
+    }
+
+    // the bound address is not used but should be readed
+    let len = match reply[3] {
+        IPV4 => 4,
+        IPV6 => 16,
+        DOMAIN => read_exact(tcp, 1)?[0] as usize,
+        atyp => {
+            return Err(Failure::Permanent(format!(
+                "Error: SOCKS: invalid address type: {}",
+                atyp
+            )))
+        }
+    };
+    read_exact(tcp, len + 2)?;
+
+    Ok(())
+}
+
+fn reply_error(reply: u8) -> &'static str {
+    match reply {
+        0x01 => "general failure",
+        0x02 => "connection not allowed by ruleset",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "TTL expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+/// SOCKS4a, the domain is sent after the user id and is resolved by the proxy
+/// there is no IPv6 and no password
+pub fn socks4a(
+    tcp: &mut TcpStream,
+    host: Host<&str>,
+    port: u16,
+    user: &str,
+) -> Result<(), Failure> {
+    let mut request = vec![4, CONNECT];
+    request.extend_from_slice(&port.to_be_bytes());
+
+    let mut domain = None;
+    match host {
+        Host::Ipv4(ip) => request.extend_from_slice(&ip.octets()),
+        // a invalid ip 0.0.0.x says that the domain is after
+        Host::Domain(name) => {
+            request.extend_from_slice(&[0, 0, 0, 1]);
+            domain = Some(name);
+        }
+        Host::Ipv6(_) => {
+            return Err(Failure::Permanent(
+                "Error: SOCKS4a does not support IPv6".to_string(),
+            ))
+        }
+    }
+
+    request.extend_from_slice(user.as_bytes());
+    request.push(0);
+    if let Some(domain) = domain {
+        request.extend_from_slice(domain.as_bytes());
+        request.push(0);
+    }
+    tcp.write_all(&request).map_err(io_err)?;
+
+    let reply = read_exact(tcp, 8)?;
+    match reply[1] {
+        0x5A => Ok(()),
+        0x5B => Err(Failure::Transient(
+            "Error: SOCKS4a: request rejected or failed".to_string(),
+        )),
+        0x5C | 0x5D => Err(Failure::Permanent(
+            "Error: SOCKS4a: identd failed".to_string(),
+        )),
+        reply => Err(Failure::Permanent(format!(
+            "Error: SOCKS4a: invalid reply: {}",
+            reply
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, thread};
+
+    use super::*;
+
+    /// A proxy that checks what was sent and answers with `replies`, one for every message
+    fn proxy(expected: Vec<Vec<u8>>, replies: Vec<Vec<u8>>) -> (TcpStream, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (mut tcp, _) = listener.accept().unwrap();
+            for (expected, reply) in expected.into_iter().zip(replies) {
+                let mut recived = vec![0; expected.len()];
+                tcp.read_exact(&mut recived).unwrap();
+                assert_eq!(recived, expected);
+                tcp.write_all(&reply).unwrap();
+            }
+        });
+        (TcpStream::connect(address).unwrap(), handle)
+    }
+
+    #[test]
+    fn socks5_domain() {
+        let (mut tcp, handle) = proxy(
+            vec![
+                vec![5, 1, NO_AUTH],
+                [&[5, CONNECT, 0, DOMAIN, 11][..], b"example.com", &[0, 80]].concat(),
+            ],
+            vec![
+                vec![5, NO_AUTH],
+                // bound to a domain, the reply has a variable length
+                [&[5, 0, 0, DOMAIN, 4][..], b"host", &[0x1F, 0x90]].concat(),
+            ],
+        );
+        socks5(&mut tcp, Host::Domain("example.com"), 80, None).unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn socks5_password() {
+        let (mut tcp, handle) = proxy(
+            vec![
+                vec![5, 2, NO_AUTH, USER_PASSWORD],
+                [&[1, 4][..], b"user", &[4], b"pass"].concat(),
+                vec![5, CONNECT, 0, IPV4, 10, 0, 0, 1, 1, 187],
+            ],
+            vec![
+                vec![5, USER_PASSWORD],
+                vec![1, 0],
+                vec![
+                    5, 0, 0, IPV6, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0,
+                ],
+            ],
+        );
+        socks5(
+            &mut tcp,
+            Host::Ipv4([10, 0, 0, 1].into()),
+            443,
+            Some(("user".to_string(), "pass".to_string())),
+        )
+        .unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn socks5_errors() {
+        let (mut tcp, handle) = proxy(
+            vec![
+                vec![5, 1, NO_AUTH],
+                vec![5, CONNECT, 0, IPV4, 127, 0, 0, 1, 0, 80],
+            ],
+            vec![vec![5, NO_AUTH], vec![5, 5, 0, IPV4, 0, 0, 0, 0, 0, 0]],
+        );
+        let err = socks5(&mut tcp, Host::Ipv4([127, 0, 0, 1].into()), 80, None).unwrap_err();
+        assert_eq!(
+            err,
+            Failure::Transient("Error: SOCKS: connection refused".to_string())
+        );
+        handle.join().unwrap();
+
+        let (mut tcp, handle) = proxy(vec![vec![5, 1, NO_AUTH]], vec![vec![5, USER_PASSWORD]]);
+        let err = socks5(&mut tcp, Host::Domain("a"), 80, None).unwrap_err();
+        assert_eq!(
+            err,
+            Failure::Permanent("Error: SOCKS: proxy wants a user and password".to_string())
+        );
+        handle.join().unwrap();
+
+        let (mut tcp, handle) = proxy(vec![vec![5, 1, NO_AUTH]], vec![vec![5, NO_ACCEPTABLE]]);
+        let err = socks5(&mut tcp, Host::Domain("a"), 80, None).unwrap_err();
+        assert_eq!(
+            err,
+            Failure::Permanent("Error: SOCKS: no acceptable authentication method".to_string())
+        );
+        handle.join().unwrap();
+
+        let (mut tcp, handle) = proxy(vec![vec![5, 1, NO_AUTH]], vec![vec![4, 0]]);
+        let err = socks5(&mut tcp, Host::Domain("a"), 80, None).unwrap_err();
+        assert_eq!(
+            err,
+            Failure::Permanent("Error: SOCKS: invalid version: 4".to_string())
+        );
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn socks4a() {
+        let (mut tcp, handle) = proxy(
+            vec![[&[4, CONNECT, 0, 80, 0, 0, 0, 1][..], b"me\0example.com\0"].concat()],
+            vec![vec![0, 0x5A, 0, 0, 0, 0, 0, 0]],
+        );
+        super::socks4a(&mut tcp, Host::Domain("example.com"), 80, "me").unwrap();
+        handle.join().unwrap();
+
+        let (mut tcp, handle) = proxy(
+            vec![vec![4, CONNECT, 0, 80, 1, 2, 3, 4, 0]],
+            vec![vec![0, 0x5B, 0, 0, 0, 0, 0, 0]],
+        );
+        let err = super::socks4a(&mut tcp, Host::Ipv4([1, 2, 3, 4].into()), 80, "").unwrap_err();
+        assert_eq!(
+            err,
+            Failure::Transient("Error: SOCKS4a: request rejected or failed".to_string())
+        );
+        handle.join().unwrap();
+
+        let mut tcp = proxy(vec![], vec![]).0;
+        assert!(super::socks4a(&mut tcp, Host::Ipv6("::1".parse().unwrap()), 80, "").is_err());
+    }
+}
@@ -0,0 +1,335 @@
+use std::{
+    io::Write,
+    time::{Duration, Instant},
+};
+
+use muzzman_lib::prelude::*;
+
+use crate::{
+    chunked::ChunkedDecoder, connection::Connection, content_encoding::ContentDecoder,
+    pool::Reusable,
+};
+
+/// Used until the server sends `retry:`
+const DEFAULT_RETRY: Duration = Duration::from_secs(3);
+
+/// A dispatched event, `data` is without the last new line
+#[derive(Clone, Debug)]
+pub struct Event {
+    pub id: Option<String>,
+    pub event: String,
+    pub data: String,
+}
+
+impl Event {
+    /// One line of JSON like `{"id":null,"event":"message","data":"..."}`
+    pub fn to_json(&self) -> String {
+        let id = match &self.id {
+            Some(id) => json_string(id),
+            None => "null".to_string(),
+        };
+        format!(
+            "{{\"id\":{},\"event\":{},\"data\":{}}}",
+            id,
+            json_string(&self.event),
+            json_string(&self.data)
+        )
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Parser for `text/event-stream` (HTML Living Standard 9.2.6), is kept in the storage between reconnects
+pub struct EventStream {
+    /// The line that is not finished
+    line: Vec<u8>,
+    after_cr: bool,
+    /// The BOM is only removed from the start of the stream
+    started: bool,
+    event: String,
+    data: String,
+    /// The id of the events, is sent in `Last-Event-ID` when reconnecting
+    pub last_event_id: Option<String>,
+    pub retry: Duration,
+    /// When the connection is dropped we wait until this to reconnect
+    reconnect_at: Option<Instant>,
+}
+
+impl Default for EventStream {
+    fn default() -> Self {
+        Self {
+            line: Vec::new(),
+            after_cr: false,
+            started: false,
+            event: String::new(),
+            data: String::new(),
+            last_event_id: None,
+            retry: DEFAULT_RETRY,
+            reconnect_at: None,
+        }
+    }
+}
+
+impl EventStream {
+    /// Returns the events that are complete, the rest is kept for the next call
+    pub fn feed(&mut self, input: &[u8]) -> Vec<Event> {
+        let mut events = Vec::new();
+        for &byte in input {
+            // `\r\n` is one line end, the `\r` already ended the line
+            let after_cr = std::mem::replace(&mut self.after_cr, byte == b'\r');
+            match byte {
+                b'\n' if after_cr => {}
+                b'\r' | b'\n' => {
+                    let line = std::mem::take(&mut self.line);
+                    if let Some(event) = self.process_line(&line) {
+                        events.push(event);
+                    }
+                }
+                byte => self.line.push(byte),
+            }
+        }
+        events
+    }
+
+    fn process_line(&mut self, line: &[u8]) -> Option<Event> {
+        let mut line = String::from_utf8_lossy(line).to_string();
+        if !self.started {
+            self.started = true;
+            if let Some(stripped) = line.strip_prefix('\u{feff}') {
+                line = stripped.to_string();
+            }
+        }
+
+        if line.is_empty() {
+            return self.dispatch();
+        }
+
+        // comments are used as keep alive
+        if line.starts_with(':') {
+            return None;
+        }
+
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line.as_str(), ""),
+        };
+
+        match field {
+            "event" => self.event = value.to_string(),
+            "data" => {
+                self.data.push_str(value);
+                self.data.push('\n');
+            }
+            "id" if !value.contains('\0') => {
+                self.last_event_id = Some(value.to_string()).filter(|id| !id.is_empty());
+            }
+            "retry" if !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()) => {
+                if let Ok(retry) = value.parse() {
+                    self.retry = Duration::from_millis(retry);
+                }
+            }
+            _ => {}
+        }
+        None
+    }
+
+    fn dispatch(&mut self) -> Option<Event> {
+        let event = std::mem::take(&mut self.event);
+        let mut data = std::mem::take(&mut self.data);
+        if data.is_empty() {
+            return None;
+        }
+        data.pop();
+
+        Some(Event {
+            id: self.last_event_id.clone(),
+            event: if event.is_empty() {
+                "message".to_string()
+            } else {
+                event
+            },
+            data,
+        })
+    }
+
+    /// A new response was started, also when was resumed while waiting to reconnect
+    pub fn connected(&mut self) {
+        self.reset();
+        self.reconnect_at = None;
+    }
+
+    /// The event that was not finished is lost
+    fn reset(&mut self) {
+        self.line.clear();
+        self.after_cr = false;
+        self.started = false;
+        self.event.clear();
+        self.data.clear();
+    }
+}
+
+/// Parses the body and writes every event as a JSON line in `element.data`
+pub fn write_events(element: &ERow, storage: &mut Storage, input: &[u8]) {
+    let Some(stream) = storage.get_mut::<EventStream>() else {
+        return;
+    };
+
+    let events = stream.feed(input);
+    if events.is_empty() {
+        return;
+    }
+
+    let mut element = element.write().unwrap();
+    for event in events.iter() {
+        let mut line = event.to_json();
+        line.push('\n');
+        element.data.write_all(line.as_bytes()).unwrap();
+    }
+
+    if let Some(Type::USize(count)) = element.settings.get_mut("sse-events") {
+        *count += events.len();
+    }
+}
+
+/// The connection was dropped, we reconnect after the `retry` interval
+pub fn disconnected(storage: &mut Storage) -> Result<(), SessionError> {
+    storage.remove::<Connection>();
+    storage.remove::<ChunkedDecoder>();
+    storage.remove::<ContentDecoder>();
+    storage.remove::<Reusable>();
+
+    let Some(stream) = storage.get_mut::<EventStream>() else {
+        return Ok(());
+    };
+
+    stream.reset();
+    stream.reconnect_at = Some(Instant::now() + stream.retry);
+    log::info!(
+        "Event stream disconnected, reconnecting in {:?} with Last-Event-ID: {:?}",
+        stream.retry,
+        stream.last_event_id
+    );
+    Ok(())
+}
+
+/// True while waiting to reconnect, when is the time the element goes back to connecting
+pub fn waiting_reconnect(element: &ERow, storage: &mut Storage) -> bool {
+    let Some(stream) = storage.get_mut::<EventStream>() else {
+        return false;
+    };
+    let Some(reconnect_at) = stream.reconnect_at else {
+        return false;
+    };
+
+    // the next ticks will check again, the session loop is not blocked
+    if Instant::now() < reconnect_at {
+        return true;
+    }
+
+    stream.reconnect_at = None;
+    element.set_status(1);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(events: &[Event]) -> Vec<&str> {
+        events.iter().map(|event| event.data.as_str()).collect()
+    }
+
+    #[test]
+    fn multi_line_data() {
+        let mut stream = EventStream::default();
+        let events = stream.feed(b"data: a\ndata:b\ndata\ndata:  c\n\n");
+        assert_eq!(data(&events), ["a\nb\n\n c"]);
+        assert_eq!(events[0].event, "message");
+        assert_eq!(events[0].id, None);
+
+        // a event without data is not dispatched, the event type is reset
+        let events = stream.feed(b"event: ping\n\ndata: x\n\n");
+        assert_eq!(data(&events), ["x"]);
+        assert_eq!(events[0].event, "message");
+    }
+
+    #[test]
+    fn fields() {
+        let mut stream = EventStream::default();
+        let events = stream.feed(b"id: 1\nevent: update\nretry: 5000\ndata: x\n\n");
+        assert_eq!(events[0].id.as_deref(), Some("1"));
+        assert_eq!(events[0].event, "update");
+        assert_eq!(stream.retry, Duration::from_secs(5));
+
+        // the id stays for the next events, a invalid retry is ignored
+        let events = stream.feed(b"retry: 1s\nid: 2\0\nother: y\ndata: y\n\n");
+        assert_eq!(events[0].id.as_deref(), Some("1"));
+        assert_eq!(stream.retry, Duration::from_secs(5));
+        assert_eq!(stream.last_event_id.as_deref(), Some("1"));
+
+        let events = stream.feed(b"id\ndata: z\n\n");
+        assert_eq!(events[0].id, None);
+    }
+
+    #[test]
+    fn comments() {
+        let mut stream = EventStream::default();
+        assert!(stream.feed(b": keep alive\n\n").is_empty());
+        let events = stream.feed(b"data: a\n: comment\ndata: b\n\n");
+        assert_eq!(data(&events), ["a\nb"]);
+    }
+
+    #[test]
+    fn line_ends() {
+        let mut stream = EventStream::default();
+        let events = stream.feed(b"data: a\r\n\r\ndata: b\r\rdata: c\n\n");
+        assert_eq!(data(&events), ["a", "b", "c"]);
+
+        // the `\r\n` can be split between two reads
+        assert!(stream.feed(b"data: d\r").is_empty());
+        assert!(stream.feed(b"\n").is_empty());
+        assert_eq!(data(&stream.feed(b"\r\n")), ["d"]);
+    }
+
+    #[test]
+    fn bom() {
+        let mut stream = EventStream::default();
+        let events = stream.feed(b"\xef\xbb\xbfdata: a\n\n\xef\xbb\xbfdata: b\n\n");
+        // only the first BOM is removed, the second is a other field name
+        assert_eq!(data(&events), ["a"]);
+
+        // after a reconnect the stream starts again
+        stream.connected();
+        let events = stream.feed(b"\xef\xbb\xbfdata: c\n\n");
+        assert_eq!(data(&events), ["c"]);
+    }
+
+    #[test]
+    fn json() {
+        let event = Event {
+            id: None,
+            event: "message".to_string(),
+            data: "a \"b\"\n\\c\u{1}".to_string(),
+        };
+        assert_eq!(
+            event.to_json(),
+            r#"{"id":null,"event":"message","data":"a \"b\"\n\\c\u0001"}"#
+        );
+    }
+}
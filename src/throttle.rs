@@ -0,0 +1,285 @@
+use std::{
+    sync::{Mutex, OnceLock},
+    time::Instant,
+};
+
+use chrono::Timelike;
+use muzzman_lib::prelude::*;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Direction {
+    Download,
+    Upload,
+}
+
+impl Direction {
+    fn setting(&self) -> &'static str {
+        match self {
+            Direction::Download => "max-download-rate",
+            Direction::Upload => "max-upload-rate",
+        }
+    }
+}
+
+/// The bucket is filled with `rate` bytes every second and can have max one second of tokens
+pub struct TokenBucket {
+    rate: usize,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: usize) -> Self {
+        Self {
+            rate,
+            tokens: rate as f64,
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+    }
+
+    fn set_rate(&mut self, rate: usize) {
+        if self.rate != rate {
+            self.refill();
+            self.rate = rate;
+            self.tokens = self.tokens.min(rate as f64);
+        }
+    }
+
+    pub fn available(&mut self) -> usize {
+        self.refill();
+        self.tokens.max(0.0) as usize
+    }
+
+    pub fn consume(&mut self, len: usize) {
+        self.tokens -= len as f64;
+    }
+}
+
+/// The buckets of a element, is in the element storage
+#[derive(Default)]
+pub struct Throttle {
+    download: Option<TokenBucket>,
+    upload: Option<TokenBucket>,
+}
+
+impl Throttle {
+    fn bucket(&mut self, direction: Direction) -> &mut Option<TokenBucket> {
+        match direction {
+            Direction::Download => &mut self.download,
+            Direction::Upload => &mut self.upload,
+        }
+    }
+}
+
+/// The module-wide buckets shared by every element: download, upload
+fn global() -> &'static Mutex<[Option<TokenBucket>; 2]> {
+    static GLOBAL: OnceLock<Mutex<[Option<TokenBucket>; 2]>> = OnceLock::new();
+    GLOBAL.get_or_init(|| Mutex::new([None, None]))
+}
+
+/// A bucket with the new rate, none when is unlimited
+fn update(bucket: &mut Option<TokenBucket>, rate: usize) {
+    if rate == 0 {
+        *bucket = None;
+    } else if let Some(bucket) = bucket {
+        bucket.set_rate(rate);
+    } else {
+        *bucket = Some(TokenBucket::new(rate));
+    }
+}
+
+/// `09:00-18:00=1M, 18:00-09:00=0` in local time, the rate is bytes per second with K, M or G,
+/// 0 is unlimited, a range can go over midnight
+pub fn parse_schedule(schedule: &str) -> Result<Vec<(u32, u32, usize)>, String> {
+    let mut ranges = Vec::new();
+    for entry in schedule
+        .split([',', ';'])
+        .map(|entry| entry.trim())
+        .filter(|entry| !entry.is_empty())
+    {
+        let invalid = || format!("Invalid rate-schedule entry: {:?}", entry);
+        let (range, rate) = entry.split_once('=').ok_or_else(invalid)?;
+        let (start, end) = range.split_once('-').ok_or_else(invalid)?;
+        let start = parse_time(start).ok_or_else(invalid)?;
+        let end = parse_time(end).ok_or_else(invalid)?;
+        let rate = parse_rate(rate).ok_or_else(invalid)?;
+        ranges.push((start, end, rate));
+    }
+    Ok(ranges)
+}
+
+/// `HH:MM` to minutes from midnight
+fn parse_time(time: &str) -> Option<u32> {
+    let (hour, minute) = time.trim().split_once(':')?;
+    let (hour, minute) = (hour.parse::<u32>().ok()?, minute.parse::<u32>().ok()?);
+    if hour > 24 || minute > 59 || (hour == 24 && minute > 0) {
+        return None;
+    }
+    Some(hour * 60 + minute)
+}
+
+/// `1M`, `512K` or only bytes, 1K is 1024
+pub fn parse_rate(rate: &str) -> Option<usize> {
+    let rate = rate.trim();
+    let (number, multiplier) = match rate.chars().last()?.to_ascii_uppercase() {
+        'K' => (&rate[..rate.len() - 1], 1024),
+        'M' => (&rate[..rate.len() - 1], 1024 * 1024),
+        'G' => (&rate[..rate.len() - 1], 1024 * 1024 * 1024),
+        _ => (rate, 1),
+    };
+    let number = number.trim().parse::<f64>().ok()?;
+    if !number.is_finite() || number < 0.0 {
+        return None;
+    }
+    Some((number * multiplier as f64) as usize)
+}
+
+/// The rate of the range that has `now`, the first that matches
+fn scheduled_rate(schedule: &str) -> Option<usize> {
+    let ranges = match parse_schedule(schedule) {
+        Ok(ranges) => ranges,
+        Err(err) => {
+            log::warn!("{}", err);
+            return None;
+        }
+    };
+
+    let now = chrono::Local::now();
+    let now = now.hour() * 60 + now.minute();
+    ranges
+        .into_iter()
+        .find(|(start, end, _)| {
+            if start <= end {
+                (*start..*end).contains(&now)
+            } else {
+                now >= *start || now < *end
+            }
+        })
+        .map(|(_, _, rate)| rate)
+}
+
+/// The `rate-schedule` when has a range for now, else `max-download-rate` or `max-upload-rate`
+fn get_rate(values: &Values, direction: Direction) -> usize {
+    if let Some(Type::String(schedule)) = values.get("rate-schedule") {
+        if let Some(rate) = scheduled_rate(schedule) {
+            return rate;
+        }
+    }
+    match values.get(direction.setting()) {
+        Some(Type::USize(rate)) => *rate,
+        _ => 0,
+    }
+}
+
+/// How much can be transferred now, max `want`, 0 if should wait
+/// the element limit and the module limit are both used, there is no sleep
+/// when is 0 the next tick of the session is trying again
+pub fn allowance(
+    element: &ERow,
+    storage: &mut Storage,
+    direction: Direction,
+    want: usize,
+) -> usize {
+    let (element_rate, module_rate) = {
+        let element = element.read().unwrap();
+        (
+            get_rate(&element.element_data, direction),
+            get_rate(&element.settings, direction),
+        )
+    };
+
+    if storage.get::<Throttle>().is_none() {
+        storage.set(Throttle::default());
+    }
+    let Some(throttle) = storage.get_mut::<Throttle>() else {
+        return want;
+    };
+    let element_bucket = throttle.bucket(direction);
+    update(element_bucket, element_rate);
+
+    let mut global = global().lock().unwrap();
+    let module_bucket = &mut global[direction as usize];
+    update(module_bucket, module_rate);
+
+    let mut allowed = want;
+    for bucket in [element_bucket, module_bucket].into_iter().flatten() {
+        // not too small chunks, a 20th of a second
+        let min_chunk = want.min((bucket.rate / 20).max(1));
+        let available = bucket.available();
+        if available < min_chunk {
+            allowed = 0;
+        } else {
+            allowed = allowed.min(available);
+        }
+    }
+    allowed
+}
+
+/// What was really transferred after `allowance`
+pub fn consume(storage: &mut Storage, direction: Direction, len: usize) {
+    if len == 0 {
+        return;
+    }
+
+    if let Some(bucket) = storage
+        .get_mut::<Throttle>()
+        .and_then(|throttle| throttle.bucket(direction).as_mut())
+    {
+        bucket.consume(len);
+    }
+
+    if let Some(bucket) = &mut global().lock().unwrap()[direction as usize] {
+        bucket.consume(len);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rates() {
+        assert_eq!(parse_rate("100"), Some(100));
+        assert_eq!(parse_rate(" 512K "), Some(512 * 1024));
+        assert_eq!(parse_rate("1m"), Some(1024 * 1024));
+        assert_eq!(parse_rate("1.5M"), Some(1024 * 1024 * 3 / 2));
+        assert_eq!(parse_rate("2G"), Some(2 * 1024 * 1024 * 1024));
+        assert_eq!(parse_rate("0"), Some(0));
+
+        for rate in ["", "K", "-1K", "1T", "fast", "inf", "NaN"] {
+            assert_eq!(parse_rate(rate), None, "{}", rate);
+        }
+    }
+
+    #[test]
+    fn schedule() {
+        assert_eq!(
+            parse_schedule("09:00-18:00=1M, 18:00-09:00=0").unwrap(),
+            vec![(540, 1080, 1024 * 1024), (1080, 540, 0)]
+        );
+        assert_eq!(
+            parse_schedule("00:00-24:00=512K;").unwrap(),
+            vec![(0, 1440, 512 * 1024)]
+        );
+        assert_eq!(parse_schedule(" ").unwrap(), vec![]);
+
+        for schedule in [
+            "09:00-18:00",
+            "09:00=1M",
+            "9-18=1M",
+            "25:00-26:00=1M",
+            "24:30-09:00=1M",
+            "09:60-18:00=1M",
+            "09:00-18:00=fast",
+        ] {
+            assert!(parse_schedule(schedule).is_err(), "{}", schedule);
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use std::{
+    net::{SocketAddr, TcpStream},
+    time::{Duration, Instant},
+};
+
+use muzzman_lib::prelude::*;
+
+use crate::retry;
+
+/// From the element settings, none is waiting forever
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Timeouts {
+    /// For the tcp connection, to the host or to the proxy
+    pub connect: Option<Duration>,
+    /// For a write to the proxy, the TLS handshake and the response headers
+    pub read: Option<Duration>,
+    /// Max time without any byte of the body, sent or recived
+    pub stall: Option<Duration>,
+}
+
+impl Timeouts {
+    /// Connects to the first adress that accepts, the socket gets the read and write timeouts
+    pub fn connect(&self, adresses: Vec<SocketAddr>) -> Option<TcpStream> {
+        for adress in adresses {
+            let connection = match self.connect {
+                Some(timeout) => TcpStream::connect_timeout(&adress, timeout),
+                None => TcpStream::connect(adress),
+            };
+            match connection {
+                Ok(connection) => {
+                    let _ = connection.set_read_timeout(self.read);
+                    let _ = connection.set_write_timeout(self.read);
+                    return Some(connection);
+                }
+                Err(err) => log::info!("Cannot connect to {}: {}", adress, err),
+            }
+        }
+        None
+    }
+}
+
+/// `connect-timeout`, `read-timeout` and `stall-timeout` in seconds, 0 is no timeout
+pub fn get_timeouts(element: &ERow) -> Timeouts {
+    let element = element.read().unwrap();
+    let get = |name: &str| match element.element_data.get(name) {
+        Some(Type::USize(secs)) if *secs > 0 => Some(Duration::from_secs(*secs as u64)),
+        _ => None,
+    };
+
+    Timeouts {
+        connect: get("connect-timeout"),
+        read: get("read-timeout"),
+        stall: get("stall-timeout"),
+    }
+}
+
+/// When the last byte of the body was transferred, is in the element storage
+pub struct Activity {
+    last: Instant,
+}
+
+/// The transfer of the body starts now
+pub fn started(storage: &mut Storage) {
+    storage.set(Activity {
+        last: Instant::now(),
+    });
+}
+
+/// Some bytes of the body came now
+pub fn recived(storage: &mut Storage, len: usize) {
+    if len == 0 {
+        return;
+    }
+    match storage.get_mut::<Activity>() {
+        Some(activity) => activity.last = Instant::now(),
+        None => started(storage),
+    }
+}
+
+/// Is called every tick with how much was recived, true if nothing came for `stall-timeout`
+pub fn stalled(element: &ERow, storage: &mut Storage, len: usize) -> bool {
+    recived(storage, len);
+    let Some(activity) = storage.get::<Activity>() else {
+        started(storage);
+        return false;
+    };
+
+    match get_timeouts(element).stall {
+        Some(stall) => len == 0 && activity.last.elapsed() >= stall,
+        None => false,
+    }
+}
+
+/// Like `stalled` but the download is retried
+pub fn check_stall(element: &ERow, storage: &mut Storage, len: usize) -> Result<(), SessionError> {
+    if !stalled(element, storage, len) {
+        return Ok(());
+    }
+
+    let stall = get_timeouts(element).stall.unwrap_or_default();
+    retry::failed(
+        element,
+        storage,
+        format!(
+            "Error: The transfer stalled, nothing recived for {}s!",
+            stall.as_secs()
+        ),
+        None,
+    )
+}
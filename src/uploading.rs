@@ -1,8 +1,13 @@
-use std::io::{Read, Write};
+use std::io::{Read, Seek, SeekFrom, Write};
 
 use muzzman_lib::prelude::*;
 
-use crate::{connection::Connection, error};
+use crate::{
+    connection::Connection,
+    error, retry,
+    throttle::{self, Direction},
+    timeouts,
+};
 
 pub fn uploading(element: &ERow, storage: &mut Storage) -> Result<(), SessionError> {
     let mut sent = 0;
@@ -10,6 +15,25 @@ pub fn uploading(element: &ERow, storage: &mut Storage) -> Result<(), SessionErr
         sent = *ptr;
     }
 
+    // the socket did not take all of the last buffer, a slow peer is waited without blocking
+    {
+        let Some(conn) = storage.get_mut::<Connection>() else {
+            element.set_status(1);
+            return Ok(());
+        };
+        if let Err(err) = conn.flush() {
+            return retry::failed(
+                element,
+                storage,
+                format!("Error: Cannot send the body: {}", err),
+                None,
+            );
+        }
+        if conn.is_pending() {
+            return timeouts::check_stall(element, storage, 0);
+        }
+    }
+
     let mut buffer_size = get_buffer_size(element)?;
 
     let content_length = get_upload_content_length(element)?;
@@ -18,6 +42,14 @@ pub fn uploading(element: &ERow, storage: &mut Storage) -> Result<(), SessionErr
         buffer_size = content_length - sent;
     }
 
+    // when is 0 the body is done and should not wait for the limit
+    if buffer_size > 0 {
+        buffer_size = throttle::allowance(element, storage, Direction::Upload, buffer_size);
+        if buffer_size == 0 {
+            return Ok(());
+        }
+    }
+
     log::info!("Sent: {}", sent);
     log::info!("Buffer size: {}", buffer_size);
 
@@ -28,24 +60,62 @@ pub fn uploading(element: &ERow, storage: &mut Storage) -> Result<(), SessionErr
         add = ford.read(&mut bytes).unwrap();
     }
 
-    if let Some(conn) = storage.get_mut::<Connection>() {
-        let _res = conn.write(&bytes[0..add]);
-    } else {
+    // all the body was sent, `creating_connection` is reading the response
+    if add == 0 {
+        element.set_status(1);
+        return Ok(());
+    }
+
+    let Some(conn) = storage.get_mut::<Connection>() else {
         element.set_status(1);
         return Ok(());
+    };
+
+    // a write takes all the buffer, only HTTP/2 can wait for the flow control window
+    let mut writen = 0;
+    while writen < add {
+        match conn.write(&bytes[writen..add]) {
+            Ok(0) => {
+                return retry::failed(
+                    element,
+                    storage,
+                    "Error: Cannot send the body: connection closed",
+                    None,
+                )
+            }
+            Ok(len) => writen += len,
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => break,
+            Err(err) => {
+                return retry::failed(
+                    element,
+                    storage,
+                    format!("Error: Cannot send the body: {}", err),
+                    None,
+                )
+            }
+        }
+    }
+    throttle::consume(storage, Direction::Upload, writen);
+    if writen == 0 {
+        return timeouts::check_stall(element, storage, 0);
     }
+    timeouts::recived(storage, writen);
 
-    sent += add;
+    // what was not writen is readed again in the next tick
+    if writen < add {
+        if let Some(Type::FileOrData(ford)) = element.write().unwrap().element_data.get_mut("body")
+        {
+            let _ = ford.seek(SeekFrom::Current(writen as i64 - add as i64));
+        }
+    }
+
+    sent += writen;
 
     if let Some(Type::USize(ptr)) = element.write().unwrap().settings.get_mut("sent") {
         *ptr = sent;
     }
 
     log::info!("New sent: {}", sent);
-
-    if add == 0 {
-        element.set_status(3);
-    }
     Ok(())
 }
 
@@ -0,0 +1,450 @@
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+};
+
+use base64::Engine;
+use muzzman_lib::prelude::*;
+use ring::rand::SecureRandom;
+use sha1::{Digest, Sha1};
+
+use crate::{connection::Connection, error, response::Headers, uploading::get_buffer_size};
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xA;
+
+const NORMAL_CLOSURE: u16 = 1000;
+const GOING_AWAY: u16 = 1001;
+const PROTOCOL_ERROR: u16 = 1002;
+const INVALID_DATA: u16 = 1007;
+const MESSAGE_TOO_BIG: u16 = 1009;
+
+/// Max size of a message, a bigger one will close the connection
+const MAX_MESSAGE: usize = 64 * 1024 * 1024;
+
+/// Max payload of a ping, pong or close
+const MAX_CONTROL: usize = 125;
+
+/// fin, opcode and payload
+type Frame = (bool, u8, Vec<u8>);
+
+fn random<const N: usize>() -> Result<[u8; N], String> {
+    let mut bytes = [0; N];
+    ring::rand::SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| "Error: WebSocket: cannot get random bytes".to_string())?;
+    Ok(bytes)
+}
+
+/// Adds the upgrade headers, returns the `Sec-WebSocket-Key` for `verify_accept`
+pub fn handshake_headers(headers: &mut HashMap<String, String>) -> Result<String, String> {
+    let key = base64::engine::general_purpose::STANDARD.encode(random::<16>()?);
+    headers.insert("Upgrade".to_string(), "websocket".to_string());
+    headers.insert("Connection".to_string(), "Upgrade".to_string());
+    headers.insert("Sec-WebSocket-Key".to_string(), key.clone());
+    headers.insert("Sec-WebSocket-Version".to_string(), "13".to_string());
+    Ok(key)
+}
+
+pub fn verify_accept(key: &str, headers: &Headers) -> Result<(), String> {
+    if !headers.contains_token("Upgrade", "websocket") {
+        return Err("WebSocket: server did not upgrade to websocket".to_string());
+    }
+
+    let mut sha1 = Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(GUID.as_bytes());
+    let expected = base64::engine::general_purpose::STANDARD.encode(sha1.finalize());
+
+    match headers.get("Sec-WebSocket-Accept") {
+        Some(accept) if accept == expected => Ok(()),
+        accept => Err(format!(
+            "WebSocket: invalid Sec-WebSocket-Accept: {:?}",
+            accept
+        )),
+    }
+}
+
+/// How the recived messages are writen in `element.data`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Framing {
+    /// One after a other
+    Append,
+    /// Every message has before his length as u64 big endian
+    LengthPrefixed,
+}
+
+pub struct WebSocket {
+    conn: Connection,
+    framing: Framing,
+    /// Send `body` as text or binary
+    text: bool,
+    buffer: Vec<u8>,
+    /// Fragments of the message that is not finished
+    message: Vec<u8>,
+    /// If the last data frame was not fin
+    receiving: bool,
+    /// If the message that is recived is text, should be UTF-8
+    receiving_text: bool,
+    /// If is sending the body, is the first frame sent
+    sending: bool,
+    close_sent: bool,
+}
+
+impl WebSocket {
+    pub fn new(conn: Connection, framing: Framing, text: bool) -> Self {
+        Self {
+            conn,
+            framing,
+            text,
+            buffer: Vec::new(),
+            message: Vec::new(),
+            receiving: false,
+            receiving_text: false,
+            sending: false,
+            close_sent: false,
+        }
+    }
+
+    fn send_frame(&mut self, fin: bool, opcode: u8, payload: &[u8]) -> std::io::Result<()> {
+        let mask = random::<4>().map_err(std::io::Error::other)?;
+        self.conn
+            .write_all(&encode_frame(fin, opcode, payload, mask))?;
+        self.conn.flush()
+    }
+
+    pub fn close(&mut self, code: u16, reason: &str) {
+        if self.close_sent {
+            return;
+        }
+        self.close_sent = true;
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(reason.as_bytes());
+        let _ = self.send_frame(true, CLOSE, &payload);
+    }
+}
+
+/// Every frame from a client should be masked
+fn encode_frame(fin: bool, opcode: u8, payload: &[u8], mask: [u8; 4]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 14);
+    frame.push(if fin { 0x80 } else { 0 } | opcode);
+
+    let len = payload.len();
+    if len < 126 {
+        frame.push(0x80 | len as u8);
+    } else if len <= u16::MAX as usize {
+        frame.push(0x80 | 126);
+        frame.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        frame.push(0x80 | 127);
+        frame.extend_from_slice(&(len as u64).to_be_bytes());
+    }
+
+    frame.extend_from_slice(&mask);
+    frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+    frame
+}
+
+/// Takes a complete frame from the start of the buffer
+fn decode_frame(buffer: &mut Vec<u8>) -> Result<Option<Frame>, (u16, String)> {
+    if buffer.len() < 2 {
+        return Ok(None);
+    }
+
+    let fin = buffer[0] & 0x80 != 0;
+    if buffer[0] & 0x70 != 0 {
+        return Err((PROTOCOL_ERROR, "reserved bits are set".to_string()));
+    }
+    let opcode = buffer[0] & 0x0f;
+    let masked = buffer[1] & 0x80 != 0;
+    if masked {
+        return Err((
+            PROTOCOL_ERROR,
+            "server frames should not be masked".to_string(),
+        ));
+    }
+
+    let (len, header) = match buffer[1] & 0x7f {
+        126 => {
+            if buffer.len() < 4 {
+                return Ok(None);
+            }
+            (u16::from_be_bytes([buffer[2], buffer[3]]) as usize, 4)
+        }
+        127 => {
+            if buffer.len() < 10 {
+                return Ok(None);
+            }
+            let mut len = [0; 8];
+            len.copy_from_slice(&buffer[2..10]);
+            (u64::from_be_bytes(len) as usize, 10)
+        }
+        len => (len as usize, 2),
+    };
+
+    // ping, pong and close
+    if opcode & 0x8 != 0 {
+        if len > MAX_CONTROL {
+            return Err((PROTOCOL_ERROR, "control frame is too big".to_string()));
+        }
+        if !fin {
+            return Err((PROTOCOL_ERROR, "control frame is fragmented".to_string()));
+        }
+    }
+
+    if len > MAX_MESSAGE {
+        return Err((MESSAGE_TOO_BIG, "frame is too big".to_string()));
+    }
+
+    if buffer.len() < header + len {
+        return Ok(None);
+    }
+
+    let payload = buffer[header..header + len].to_vec();
+    buffer.drain(0..header + len);
+    Ok(Some((fin, opcode, payload)))
+}
+
+/// Sends the body and writes the recived messages in `element.data`
+pub fn downloading_websocket(element: &ERow, storage: &mut Storage) -> Result<(), SessionError> {
+    let buffer_size = get_buffer_size(element)?;
+
+    let Some(ws) = storage.get_mut::<WebSocket>() else {
+        return Ok(());
+    };
+
+    // sending the body, every tick a fragment
+    let mut fragment = vec![0; buffer_size];
+    let mut add = 0;
+    if let Some(Type::FileOrData(ford)) = element.write().unwrap().element_data.get_mut("body") {
+        add = ford.read(&mut fragment).unwrap_or(0);
+    }
+
+    if add > 0 || ws.sending {
+        let opcode = if ws.sending {
+            CONTINUATION
+        } else if ws.text {
+            TEXT
+        } else {
+            BINARY
+        };
+        // the body has ended when nothing more can be readed
+        let fin = add == 0;
+        if ws.send_frame(fin, opcode, &fragment[0..add]).is_err() {
+            return Err(error(element, "Error: WebSocket: cannot send"));
+        }
+        ws.sending = !fin;
+
+        if let Some(Type::USize(sent)) = element.write().unwrap().settings.get_mut("sent") {
+            *sent += add;
+        }
+    }
+
+    let mut buffer = vec![0; buffer_size];
+    let len = match ws.conn.read(&mut buffer) {
+        Ok(len) => len,
+        Err(err) => match err.kind() {
+            std::io::ErrorKind::WouldBlock => return Ok(()),
+            _ => return Err(error(element, "Error: Connection close unexpected!")),
+        },
+    };
+
+    if len == 0 {
+        return Err(error(
+            element,
+            "Error: WebSocket: connection closed without close frame!",
+        ));
+    }
+    ws.buffer.extend_from_slice(&buffer[0..len]);
+
+    if let Some(Type::USize(recv)) = element.write().unwrap().settings.get_mut("recv") {
+        *recv += len;
+    }
+
+    loop {
+        let (fin, opcode, payload) = match decode_frame(&mut ws.buffer) {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            Err((code, reason)) => {
+                ws.close(code, &reason);
+                return Err(error(element, format!("Error: WebSocket: {}", reason)));
+            }
+        };
+
+        match opcode {
+            PING => {
+                if ws.send_frame(true, PONG, &payload).is_err() {
+                    return Err(error(element, "Error: WebSocket: cannot send pong"));
+                }
+            }
+            PONG => {}
+            CLOSE => {
+                let code = if payload.len() >= 2 {
+                    u16::from_be_bytes([payload[0], payload[1]])
+                } else {
+                    NORMAL_CLOSURE
+                };
+                let reason = String::from_utf8_lossy(payload.get(2..).unwrap_or_default());
+                log::info!("WebSocket closed: {} {}", code, reason);
+
+                ws.close(code, "");
+                element.write().unwrap().settings.set(
+                    "websocket-close",
+                    Type::String(format!("{} {}", code, reason)),
+                );
+
+                if code == NORMAL_CLOSURE || code == GOING_AWAY {
+                    storage.remove::<WebSocket>();
+                    element.set_status(8);
+                    return Ok(());
+                }
+                return Err(error(
+                    element,
+                    format!("Error: WebSocket closed: {} {}", code, reason),
+                ));
+            }
+            TEXT | BINARY | CONTINUATION => {
+                if (opcode == CONTINUATION) != ws.receiving {
+                    ws.close(PROTOCOL_ERROR, "invalid fragmentation");
+                    return Err(error(element, "Error: WebSocket: invalid fragmentation"));
+                }
+                if ws.message.len() + payload.len() > MAX_MESSAGE {
+                    ws.close(MESSAGE_TOO_BIG, "");
+                    return Err(error(element, "Error: WebSocket: message is too big"));
+                }
+                if opcode != CONTINUATION {
+                    ws.receiving_text = opcode == TEXT;
+                }
+                ws.message.extend_from_slice(&payload);
+                ws.receiving = !fin;
+
+                if fin {
+                    let message = std::mem::take(&mut ws.message);
+                    if ws.receiving_text && std::str::from_utf8(&message).is_err() {
+                        ws.close(INVALID_DATA, "");
+                        return Err(error(
+                            element,
+                            "Error: WebSocket: text message is not UTF-8",
+                        ));
+                    }
+                    let mut element = element.write().unwrap();
+                    if ws.framing == Framing::LengthPrefixed {
+                        element
+                            .data
+                            .write_all(&(message.len() as u64).to_be_bytes())
+                            .unwrap();
+                    }
+                    element.data.write_all(&message).unwrap();
+                }
+            }
+            opcode => {
+                ws.close(PROTOCOL_ERROR, "unknown opcode");
+                return Err(error(
+                    element,
+                    format!("Error: WebSocket: unknown opcode: {}", opcode),
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A frame from the server, is not masked
+    fn server_frame(first: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![first];
+        if payload.len() < 126 {
+            frame.push(payload.len() as u8);
+        } else {
+            frame.push(126);
+            frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        }
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn encode() {
+        let mask = [1, 2, 3, 4];
+        assert_eq!(
+            encode_frame(true, TEXT, b"Hi", mask),
+            vec![0x81, 0x82, 1, 2, 3, 4, b'H' ^ 1, b'i' ^ 2]
+        );
+
+        let frame = encode_frame(false, BINARY, &[0; 200], mask);
+        assert_eq!(&frame[0..4], &[0x02, 0x80 | 126, 0, 200]);
+        assert_eq!(frame.len(), 4 + 4 + 200);
+
+        let frame = encode_frame(true, BINARY, &vec![0; 70000], mask);
+        assert_eq!(&frame[0..2], &[0x82, 0x80 | 127]);
+        assert_eq!(&frame[2..10], &70000u64.to_be_bytes());
+    }
+
+    #[test]
+    fn decode() {
+        let mut buffer = server_frame(0x81, b"Hello");
+        buffer.extend_from_slice(&server_frame(0x02, &[7; 300]));
+        buffer.extend_from_slice(&server_frame(0x80, b"!"));
+
+        assert_eq!(
+            decode_frame(&mut buffer),
+            Ok(Some((true, TEXT, b"Hello".to_vec())))
+        );
+        assert_eq!(
+            decode_frame(&mut buffer),
+            Ok(Some((false, BINARY, vec![7; 300])))
+        );
+        assert_eq!(
+            decode_frame(&mut buffer),
+            Ok(Some((true, CONTINUATION, b"!".to_vec())))
+        );
+        assert_eq!(decode_frame(&mut buffer), Ok(None));
+    }
+
+    #[test]
+    fn decode_split() {
+        let frame = server_frame(0x82, &[1; 200]);
+        let mut buffer = Vec::new();
+        for byte in &frame[..frame.len() - 1] {
+            buffer.push(*byte);
+            assert_eq!(decode_frame(&mut buffer), Ok(None));
+        }
+        buffer.push(frame[frame.len() - 1]);
+        assert_eq!(
+            decode_frame(&mut buffer),
+            Ok(Some((true, BINARY, vec![1; 200])))
+        );
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn invalid_frames() {
+        let error = |mut buffer: Vec<u8>| decode_frame(&mut buffer).unwrap_err().0;
+
+        // masked, reserved bits
+        assert_eq!(
+            error(vec![0x81, 0x80 | 1, 0, 0, 0, 0, b'a']),
+            PROTOCOL_ERROR
+        );
+        assert_eq!(error(server_frame(0xC1, b"a")), PROTOCOL_ERROR);
+
+        // a control frame is max 125 and not fragmented
+        assert_eq!(error(server_frame(0x89, &[0; 126])), PROTOCOL_ERROR);
+        assert_eq!(error(server_frame(0x09, b"ping")), PROTOCOL_ERROR);
+        assert!(decode_frame(&mut server_frame(0x89, &[0; 125])).is_ok());
+
+        let mut too_big = vec![0x82, 127];
+        too_big.extend_from_slice(&(MAX_MESSAGE as u64 + 1).to_be_bytes());
+        assert_eq!(error(too_big), MESSAGE_TOO_BIG);
+    }
+}